@@ -1,9 +1,11 @@
 use bitcoin::address::NetworkUnchecked;
+use bitcoin::Network;
 use std::{hash::Hash, str::FromStr};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::coins::bitcoin::{Btc, MyBtcAddr};
+use crate::coins::bitcoin::MyBtcAddr;
+use crate::p2p::networking::hard_config::{DEV_ADDRESS_BTC_STR, DEV_ADDRESS_BTC_TEST_STR};
 pub trait Address:
     'static
     + Eq
@@ -17,11 +19,14 @@ pub trait Address:
     + Sync
 {
     type FromScript;
+    type Network: Copy;
     type Error: std::fmt::Debug;
 
-    fn from_script(s: &Self::FromScript) -> Result<Self, Self::Error>;
-    fn from_string(s: &str) -> Result<Self, Self::Error>;
+    fn from_script(s: &Self::FromScript, network: Self::Network) -> Result<Self, Self::Error>;
+    fn from_string(s: &str, network: Self::Network) -> Result<Self, Self::Error>;
     fn to_script(&self) -> Self::FromScript;
+    // the address of the genesis fee shares
+    fn dev_address(network: Self::Network) -> Self;
 }
 
 #[derive(Debug)]
@@ -33,10 +38,11 @@ pub enum BtcAddrError {
 pub struct BtcLikeAddr;
 impl Address for MyBtcAddr {
     type FromScript = bitcoin::script::ScriptBuf;
+    type Network = Network;
     type Error = BtcAddrError;
 
-    fn from_script(s: &Self::FromScript) -> Result<Self, Self::Error> {
-        let inner = bitcoin::Address::from_script(&s, Btc::NETWORK)?;
+    fn from_script(s: &Self::FromScript, network: Network) -> Result<Self, Self::Error> {
+        let inner = bitcoin::Address::from_script(&s, network)?;
         Ok(MyBtcAddr(inner))
     }
 
@@ -44,11 +50,25 @@ impl Address for MyBtcAddr {
         self.0.script_pubkey()
     }
 
-    fn from_string(s: &str) -> Result<Self, Self::Error> {
+    fn from_string(s: &str, network: Network) -> Result<Self, Self::Error> {
         Ok(MyBtcAddr(
-            bitcoin::Address::<NetworkUnchecked>::from_str(s)?.require_network(Btc::NETWORK)?,
+            bitcoin::Address::<NetworkUnchecked>::from_str(s)?.require_network(network)?,
         ))
     }
+
+    fn dev_address(network: Network) -> Self {
+        // the test networks share a key
+        let dev = match network {
+            Network::Bitcoin => DEV_ADDRESS_BTC_STR,
+            _ => DEV_ADDRESS_BTC_TEST_STR,
+        };
+        let script = bitcoin::Address::<NetworkUnchecked>::from_str(dev)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+
+        Self::from_script(&script, network).unwrap()
+    }
 }
 
 impl From<bitcoin::address::ParseError> for BtcAddrError {
@@ -76,3 +96,26 @@ impl From<bitcoin::address::Error> for BtcAddrError {
 
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+
+    use crate::{
+        address::Address, coins::bitcoin::MyBtcAddr,
+        p2p::networking::bitcoin::tests::sample_payout_script,
+    };
+
+    #[test]
+    fn addresses_are_network_checked() {
+        let regtest = MyBtcAddr::from_script(&sample_payout_script(), Network::Regtest).unwrap();
+        let encoded = regtest.0.to_string();
+
+        assert!(encoded.starts_with("bcrt1"));
+        assert!(MyBtcAddr::from_string(&encoded, Network::Regtest).is_ok());
+        assert!(MyBtcAddr::from_string(&encoded, Network::Bitcoin).is_err());
+
+        let dev = MyBtcAddr::dev_address(Network::Bitcoin);
+        assert!(dev.0.to_string().starts_with("bc1"));
+    }
+}
@@ -5,7 +5,7 @@ use crypto_bigint::U256;
 use serde::{Deserialize, Serialize};
 
 
-use crate::p2p::consensus::consensus::ConsensusConfigP2P;
+use crate::p2p::consensus::consensus::{ConsensusConfigP2P, RetargetAlgorithm};
 
 use super::coin::Coin;
 
@@ -31,34 +31,31 @@ impl Coin for Btc {
     const DEFAULT_P2P_PORT: u16 = 18332;
     const DEFAULT_STRATUM_PORT: u16 = 28332;
 
-    fn main_pool_consensus_config() -> ConsensusConfigP2P<Self::BlockT> {
+    fn main_pool_consensus_config(network: Network) -> ConsensusConfigP2P<Self::BlockT> {
         ConsensusConfigP2P {
+            network,
             parent_pool_hash: U256::ZERO,
             block_time_ms: Duration::from_secs(10).as_millis() as u32,
             diff_adjust_blocks: 16,
-            genesis_block: bitcoin::blockdata::constants::genesis_block(Network::Bitcoin),
+            genesis_block: bitcoin::blockdata::constants::genesis_block(network),
             password: None,
             target_1: Self::DIFF1,
             name: String::from("main"),
             default_port_p2p: Self::DEFAULT_P2P_PORT,
             default_port_stratum: Self::DEFAULT_STRATUM_PORT,
+            retarget: RetargetAlgorithm::default(),
         }
     }
 }
 
-impl Btc {
-    pub const NETWORK: Network = Network::Regtest;
-}
-
 impl<'de> Deserialize<'de> for MyBtcAddr {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
+        // only addresses we've already checked are serialized
         Ok(MyBtcAddr(
-            bitcoin::Address::<NetworkUnchecked>::deserialize(deserializer)?
-                .require_network(Btc::NETWORK)
-                .unwrap(),
+            bitcoin::Address::<NetworkUnchecked>::deserialize(deserializer)?.assume_checked(),
         ))
     }
 }
@@ -15,13 +15,16 @@ use crate::{
         config::{ConfigP2P},
         protocol::ProtocolP2P,
     }, consensus::consensus::ConsensusConfigP2P},
-    stratum::{config::StratumConfig, job_fetcher::BlockFetcher},
+    stratum::{config::StratumConfig, job_fetcher::{BlockFetcher, DaemonAuth}, vardiff::VardiffConfig},
 };
 
 // todo remove the clone. and debug
 pub trait Coin: Clone + Debug + PartialEq {
     type BlockT: Block;
-    type Address: Address<FromScript = <Self::BlockT as Block>::Script>;
+    type Address: Address<
+        FromScript = <Self::BlockT as Block>::Script,
+        Network = <Self::BlockT as Block>::Network,
+    >;
     type Fetcher: BlockFetcher<Self::BlockT> + Debug;
     const DONATION_ADDRESS: &'static str;
     const NAME: &'static str;
@@ -31,8 +34,13 @@ pub trait Coin: Clone + Debug + PartialEq {
     const DEFAULT_P2P_PORT: u16;
     const DEFAULT_STRATUM_PORT: u16;
 
-    fn main_pool_consensus_config() -> ConsensusConfigP2P<Self::BlockT>;
-    fn main_pool_config(data_dir: Box<Path>) -> ProtocolServerConfig<ConfigP2P<Self::BlockT>> {
+    fn main_pool_consensus_config(
+        network: <Self::BlockT as Block>::Network,
+    ) -> ConsensusConfigP2P<Self::BlockT>;
+    fn main_pool_config(
+        data_dir: Box<Path>,
+        network: <Self::BlockT as Block>::Network,
+    ) -> ProtocolServerConfig<ConfigP2P<Self::BlockT>> {
         ProtocolServerConfig {
             server_config: ServerConfig {
                 address: SocketAddr::V4(SocketAddrV4::new(
@@ -42,9 +50,10 @@ pub trait Coin: Clone + Debug + PartialEq {
                 processing_threads: 1,
             },
             protocol_config: ConfigP2P {
-                consensus: Self::main_pool_consensus_config(),
+                consensus: Self::main_pool_consensus_config(network),
                 max_peer_connections: 16,
                 rpc_url: format!("http://127.0.0.1:{}", Self::DEFAULT_DAEMON_PORT),
+                rpc_auth: DaemonAuth::default(),
                 data_dir, /* : Path::new(&format!("./data/{}", Self::NAME)).into() */
                 listening_port: Self::DEFAULT_P2P_PORT,
             },
@@ -58,8 +67,11 @@ pub trait Coin: Clone + Debug + PartialEq {
             },
             protocol_config: StratumConfig {
                 rpc_url: format!("http://127.0.0.1:{}", Self::DEFAULT_DAEMON_PORT),
+                rpc_auth: DaemonAuth::default(),
                 job_poll_interval_ms: Duration::from_secs(1).as_millis() as u64,
                 default_diff_units: 10000,
+                vardiff: VardiffConfig::default(),
+                zmq_hashblock: None,
             },
         }
     }
@@ -0,0 +1,67 @@
+// splits the raw tcp byte stream of a connection into protocol messages (frames)
+pub trait Framing {
+    // pops the first complete frame out of the buffer, Ok(None) if more bytes are needed
+    fn decode(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError>;
+    fn encode(payload: &[u8]) -> Vec<u8>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    BadMagic,
+    BadChecksum,
+    TooLarge(usize),
+}
+
+// stratum lines are tiny, anything bigger than this is garbage
+pub const MAX_LINE_SIZE: usize = 64 * 1024;
+
+// newline delimited, used by json rpc (stratum)
+pub struct LineFraming;
+
+impl Framing for LineFraming {
+    fn decode(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                let mut line: Vec<u8> = buf.drain(..=pos).collect();
+                // remove delimiter
+                line.pop();
+                Ok(Some(line))
+            }
+            None if buf.len() > MAX_LINE_SIZE => Err(FrameError::TooLarge(buf.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(payload.len() + 1);
+        bytes.extend_from_slice(payload);
+        bytes.push(b'\n');
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameError, Framing, LineFraming, MAX_LINE_SIZE};
+
+    #[test]
+    fn line_partial_and_multiple() {
+        let mut buf = b"{\"id\": 1}\n{\"id\"".to_vec();
+
+        assert_eq!(LineFraming::decode(&mut buf), Ok(Some(b"{\"id\": 1}".to_vec())));
+        assert_eq!(LineFraming::decode(&mut buf), Ok(None));
+
+        buf.extend_from_slice(b": 2}\n");
+        assert_eq!(LineFraming::decode(&mut buf), Ok(Some(b"{\"id\": 2}".to_vec())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn line_too_large() {
+        let mut buf = vec![b'a'; MAX_LINE_SIZE + 1];
+        assert_eq!(
+            LineFraming::decode(&mut buf),
+            Err(FrameError::TooLarge(MAX_LINE_SIZE + 1))
+        );
+    }
+}
@@ -3,6 +3,7 @@ pub mod protocol_server;
 pub mod server;
 pub mod stratum;
 pub mod protocol;
+pub mod framing;
 pub mod p2p;
 pub mod config;
 pub mod coins;
@@ -5,37 +5,83 @@ use std::collections::HashMap;
 use std::io::Read;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 use std::{fs, path::Path};
 
 use crate::coins::coin::Coin;
 use crate::p2p::networking::block::Block;
-use crate::p2p::networking::difficulty::get_diff_score;
+use crate::p2p::networking::difficulty::{get_diff_score, get_target_work, MAX_TARGET};
 use crate::p2p::networking::pplns::ScoreChanges;
 use crate::p2p::networking::share::ShareP2P;
 use crate::stratum::header::BlockHeader;
+use crate::address::Address;
 use crypto_bigint::U256;
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::p2p::networking::block::EncodeErrorP2P;
 
 use crate::p2p::networking::messages::ShareVerificationError;
 use crate::p2p::networking::pplns::{self, Score, WindowPPLNS};
 
-use super::target_manager::TargetManager;
+use crate::p2p::networking::hard_config::{
+    MAX_FUTURE_SHARE_SECS, MAX_ORPHAN_SHARES, MAX_REORG_DEPTH, MEDIAN_TIME_SHARES,
+    ORPHAN_EXPIRY_SECS, SYNC_PAGE_SIZE,
+};
+use crate::p2p::networking::utils::time_now_ms;
+
+use super::orphan_pool::{Orphan, OrphanPool};
+use super::share_tree::{ShareNode, ShareTree};
+use super::target_manager::{Adjustment, TargetManager};
 
 // we don't need the entire block for verification...
 pub struct BlockVerifyContext {
     hash: U256,
+    // what the header's bits must say for a share on top of it
+    target: U256,
 }
 
 pub struct BlockManager<C: Coin> {
     shares_dir: Box<Path>,
+    rounds_dir: Box<Path>,
     p2p_tip: Mutex<ProcessedShare<C>>,
+    share_tree: Mutex<ShareTree<C>>,
+    orphans: Mutex<OrphanPool<C::BlockT>>,
     main_tip: Mutex<BlockVerifyContext>,
     current_height: AtomicU32,
 
     round_start_height: AtomicU32,
     round_num: AtomicU32,
+    network: <C::BlockT as Block>::Network,
+}
+
+// live shares are mined on top of the current main chain tip,
+// historic ones (received while syncing) were mined on older main blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareOrigin {
+    Live,
+    Historic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TipUpdate {
+    // the share extends the best chain
+    Extended,
+    // the share's branch became heavier than the best chain
+    Reorg { depth: u32 },
+    // valid, but on a lighter branch
+    SideChain,
+}
+
+// what each address got paid in a round
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoundRecord<A> {
+    pub round_num: u32,
+    pub start_height: u32,
+    pub end_height: u32,
+    // the share that met its main target, whether the daemon took it as a block isn't part of the share chain
+    pub closing_share: U256,
+    pub payouts: Vec<(A, u64)>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -46,35 +92,56 @@ pub struct ProcessedShare<C: Coin> {
 }
 
 impl<C: Coin> BlockManager<C> {
-    pub fn new(genesis: ShareP2P<C>, data_dir: Box<Path>) -> Self {
+    pub fn new(
+        genesis: ShareP2P<C>,
+        genesis_adjustment: Adjustment,
+        data_dir: Box<Path>,
+        network: <C::BlockT as Block>::Network,
+    ) -> Self {
         // let genesis: ShareP2P<C> = ShareP2P::from_genesis_block(fetcher);
 
+        let mut rounds_dir = data_dir.clone().to_path_buf();
+        rounds_dir.push("rounds");
+        let rounds_dir = rounds_dir.into_boxed_path();
+
         let mut data_dir = data_dir.clone().to_path_buf();
         data_dir.push("shares");
         let hash = genesis.block.get_header().get_hash();
 
         let blocks_dir = data_dir.into_boxed_path();
 
-        if let Err(e) = fs::create_dir_all(&blocks_dir) {
-            if e.kind() != std::io::ErrorKind::AlreadyExists {
-                panic!("Failed to create blocks dir: {e}");
+        for dir in [&blocks_dir, &rounds_dir] {
+            if let Err(e) = fs::create_dir_all(dir) {
+                if e.kind() != std::io::ErrorKind::AlreadyExists {
+                    panic!("Failed to create data dir: {e}");
+                }
             }
         }
 
+        let genesis_share = ProcessedShare {
+            inner: genesis,
+            hash,
+            // doesnt matter
+            score: 0,
+        };
+
         Self {
             shares_dir: blocks_dir,
+            rounds_dir,
             main_tip: Mutex::new(BlockVerifyContext {
-                hash: genesis.block.get_header().get_hash(),
-            }),
-            p2p_tip: Mutex::new(ProcessedShare {
-                inner: genesis,
-                hash,
-                // doesnt matter
-                score: 0,
+                hash: genesis_share.inner.block.get_header().get_hash(),
+                target: genesis_share.inner.block.get_header().get_target(),
             }),
+            share_tree: Mutex::new(ShareTree::new(genesis_share.clone(), genesis_adjustment)),
+            p2p_tip: Mutex::new(genesis_share),
+            orphans: Mutex::new(OrphanPool::new(
+                MAX_ORPHAN_SHARES,
+                Duration::from_secs(ORPHAN_EXPIRY_SECS),
+            )),
             current_height: AtomicU32::new(0),
             round_start_height: AtomicU32::new(0),
             round_num: AtomicU32::new(0),
+            network,
         }
     }
 
@@ -100,6 +167,7 @@ impl<C: Coin> BlockManager<C> {
     pub fn decode_share(
         block: C::BlockT,
         last_scores: &HashMap<C::Address, u64>,
+        network: <C::BlockT as Block>::Network,
     ) -> Result<ShareP2P<C>, ShareVerificationError> {
         let current_scores = Self::get_scores(&block);
         let p2p_encoded = block.deserialize_p2p_encoded()?;
@@ -107,7 +175,7 @@ impl<C: Coin> BlockManager<C> {
         Ok(ShareP2P {
             block,
             encoded: p2p_encoded,
-            score_changes: ScoreChanges::new(current_scores, last_scores.clone())?,
+            score_changes: ScoreChanges::new(current_scores, last_scores.clone(), network)?,
         })
     }
 
@@ -115,69 +183,362 @@ impl<C: Coin> BlockManager<C> {
         &self,
         block: C::BlockT,
         p2ptarget: &TargetManager,
-        window: &WindowPPLNS<C>,
-    ) -> Result<ProcessedShare<C>, ShareVerificationError> {
+        window: &mut WindowPPLNS<C>,
+        origin: ShareOrigin,
+    ) -> Result<(ProcessedShare<C>, TipUpdate), ShareVerificationError> {
         let mut p2p_tip = self.p2p_tip.lock().unwrap();
+        let mut tree = self.share_tree.lock().unwrap();
 
-        let p2ptarget = p2ptarget.target();
+        let hash = block.get_header().get_hash();
+        if tree.contains(&hash) {
+            return Err(ShareVerificationError::AlreadyKnown);
+        }
 
-        
-        let share: ShareP2P<C> = Self::decode_share(block, &window.address_scores)?;
-        
-        let main_hash = self.main_tip().hash;
+        let check_height = match origin {
+            ShareOrigin::Live => {
+                let main_tip = self.main_tip();
+
+                // check mainnet link
+                if block.get_header().get_prev() != main_tip.hash {
+                    info!("GIVEN PREV: {}", block.get_header().get_prev());
+                    info!("EXP PREV: {}", main_tip.hash);
+                    return Err(ShareVerificationError::BadLinkMain);
+                }
+                // easier bits would let every share close a round
+                if block.get_header().get_target() != main_tip.target {
+                    return Err(ShareVerificationError::BadTarget);
+                }
+                Some(self.height())
+            }
+            ShareOrigin::Historic => {
+                // the main tip it was mined on is gone, but it can't claim a height past ours
+                let main_height = block
+                    .get_main_height()
+                    .ok_or(ShareVerificationError::BadLinkMain)?;
+                let current_height = self.height();
+                if current_height > 0 && main_height > current_height {
+                    return Err(ShareVerificationError::BadLinkMain);
+                }
+                Some(main_height)
+            }
+        };
 
-        // check mainnet link
-        if share.block.get_header().get_prev() != main_hash {
-            info!("GIVEN PREV: {}", share.block.get_header().get_prev());
-            info!("EXP PREV: {}", main_hash);
+        if !block.verify_main_consensus(check_height, self.network) {
             return Err(ShareVerificationError::BadLinkMain);
         }
 
-        if !share.block.verify_main_consensus(self.height()) {
-            return Err(ShareVerificationError::BadLinkMain);
+        let encoded = block.deserialize_p2p_encoded()?;
+
+        // orphans can't be checked against their parent's target yet, but must at least meet the easiest one
+        if hash > MAX_TARGET {
+            return Err(ShareVerificationError::BadTarget);
         }
 
         // check p2p link
-        if share.encoded.prev_hash != p2p_tip.hash
-            || share.encoded.height != p2p_tip.inner.encoded.height + 1
-            || share.encoded.round_num != p2p_tip.inner.encoded.round_num
-        {
+        let (parent_height, round, round_start, parent_adjustment) =
+            match tree.get(&encoded.prev_hash) {
+                Some(parent) => {
+                    // the main chain never goes back under a share chain, the genesis share isn't mined on it
+                    let parent_header = parent.share.inner.block.get_header();
+                    if parent.share.inner.encoded.height > 0
+                        && parent.share.inner.block.get_main_height() > check_height
+                    {
+                        return Err(ShareVerificationError::BadLinkMain);
+                    }
+                    // the main bits only change with the main block, synced shares are held to their parent's
+                    if parent_header.get_prev() == block.get_header().get_prev()
+                        && parent_header.get_target() != block.get_header().get_target()
+                    {
+                        return Err(ShareVerificationError::BadTarget);
+                    }
+
+                    // the share after a found block starts the next round
+                    let (round, round_start) = Self::next_round(parent);
+                    (
+                        parent.share.inner.encoded.height,
+                        round,
+                        round_start,
+                        parent.adjustment.clone(),
+                    )
+                }
+                None => {
+                    return self.add_orphan(&tree, block, hash, encoded.prev_hash, encoded.height, origin)
+                }
+            };
+
+        if encoded.height != parent_height + 1 || encoded.round_num != round {
             return Err(ShareVerificationError::BadLinkP2P);
         }
 
-        let hash = share.block.get_header().get_hash();
-        if &hash > p2ptarget {
+        // the target follows the retarget schedule of the share's own branch
+        if hash > parent_adjustment.target {
             warn!(
                 "Insufficient diffiuclty: given {}, target: {}",
-                &hash, p2ptarget
+                &hash, parent_adjustment.target
             );
 
             return Err(ShareVerificationError::BadTarget);
         }
+        // the retarget trusts the share times, so they can't go back or run ahead
+        let time = block.get_header().get_time();
+        if time <= Self::median_time_past(&tree, &encoded.prev_hash)
+            || time as u64 > time_now_ms() / 1000 + MAX_FUTURE_SHARE_SECS
+        {
+            return Err(ShareVerificationError::BadTime);
+        }
+
+        let history: Vec<(u32, U256)> = tree
+            .ancestors(&encoded.prev_hash)
+            .take(p2ptarget.history_len() as usize)
+            .map(|node| (node.share.inner.block.get_header().get_time(), node.adjustment.target))
+            .collect();
+        let adjustment = p2ptarget.next_adjustment(
+            &parent_adjustment,
+            &history,
+            encoded.height,
+            time,
+        );
 
         // share score is: share_diff / target_diff
-        let score = get_diff_score(&hash, &share.block.get_header().get_target());
+        let score = get_diff_score(&hash, &block.get_header().get_target());
+        // a lucky hash pays more, but only the target counts towards the chain, like bitcoin's chainwork
+        let work = get_target_work(&parent_adjustment.target);
         // println!("Share score: {}", score);
         // info!("HASH: {}", hash);
 
-        if window.verify_changes(&share.score_changes, score) {
-            warn!("Score changes are unbalanced...");
-            return Err(ShareVerificationError::BadRewards);
+        // the share's score changes are relative to the window at its parent, which might be on another branch
+        let best_hash = tree.best_hash();
+        let (disconnect, connect) = tree
+            .path(&best_hash, &encoded.prev_hash)
+            .ok_or(ShareVerificationError::BadLinkP2P)?;
+
+        if disconnect.len() > window.rewind_depth() {
+            warn!("Share forks off too deep: {} shares", disconnect.len());
+            return Err(ShareVerificationError::BadLinkP2P);
         }
+        Self::move_window(&tree, window, &disconnect, &connect);
 
-        let _ = self.save_share(&share);
+        let share = match Self::decode_share(block, &window.address_scores, self.network) {
+            Ok(share) if window.verify_changes(&share.score_changes, score) => {
+                warn!("Score changes are unbalanced...");
+                Err(ShareVerificationError::BadRewards)
+            }
+            res => res,
+        };
+
+        let share = match share {
+            Ok(k) => k,
+            Err(e) => {
+                Self::move_window(&tree, window, &connect, &disconnect);
+                return Err(e);
+            }
+        };
 
         let res = ProcessedShare {
             inner: share,
             score,
             hash,
         };
-        *p2p_tip = res.clone();
-        info!("New p2p tip, score: {}, hash: {}", score, hash);
+        tree.insert(res.clone(), work, adjustment, round_start);
+
+        if !tree.outweighs_best(&hash) {
+            Self::move_window(&tree, window, &connect, &disconnect);
+            info!("New side chain share, score: {}, hash: {}", score, hash);
+            return Ok((res, TipUpdate::SideChain));
+        }
+
+        window.add(res.clone());
+        for connected in connect.iter().chain(std::iter::once(&hash)) {
+            let node = tree.get(connected).unwrap();
+            let _ = self.save_share(&node.share.inner);
+
+            if Self::meets_main_target(&node.share) {
+                self.close_round(node);
+            }
+        }
+
+        tree.set_best(hash);
+        let (next_round, next_round_start) = Self::next_round(tree.best());
+        self.round_num.store(next_round, Ordering::Relaxed);
         self.round_start_height
-            .store(self.round_start_height() + 1, Ordering::Relaxed);
+            .store(next_round_start, Ordering::Relaxed);
+        // retargeting might need to look back further than reorgs can
+        tree.prune(encoded.height.saturating_sub(MAX_REORG_DEPTH.max(p2ptarget.history_len())));
+
+        // a shorter but heavier chain leaves the old one's files above it, a restart would replay them
+        for stale in encoded.height + 1..=p2p_tip.inner.encoded.height {
+            let _ = fs::remove_file(self.get_share_path(stale));
+        }
+        *p2p_tip = res.clone();
+
+        let update = if disconnect.is_empty() {
+            info!("New p2p tip, score: {}, hash: {}", score, hash);
+            TipUpdate::Extended
+        } else {
+            warn!(
+                "P2P reorg! disconnected {} shares, connected {}, new tip: {}",
+                disconnect.len(),
+                connect.len() + 1,
+                hash
+            );
+            TipUpdate::Reorg {
+                depth: disconnect.len() as u32,
+            }
+        };
+
+        Ok((res, update))
+    }
+
+    // whether the share is good enough for a main chain block, the genesis doesn't count.
+    // rounds are part of the share chain, so only the share and its checked bits decide, not what some node's daemon says
+    fn meets_main_target(share: &ProcessedShare<C>) -> bool {
+        share.inner.encoded.height > 0 && share.hash <= share.inner.block.get_header().get_target()
+    }
 
-        Ok(res)
+    fn median_time_past(tree: &ShareTree<C>, parent: &U256) -> u32 {
+        let mut times: Vec<u32> = tree
+            .ancestors(parent)
+            .take(MEDIAN_TIME_SHARES)
+            .map(|node| node.share.inner.block.get_header().get_time())
+            .collect();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    // the round and its start height of the share's children
+    fn next_round(parent: &ShareNode<C>) -> (u32, u32) {
+        let encoded = &parent.share.inner.encoded;
+        if Self::meets_main_target(&parent.share) {
+            (encoded.round_num + 1, encoded.height + 1)
+        } else {
+            (encoded.round_num, parent.round_start)
+        }
+    }
+
+    fn close_round(&self, node: &ShareNode<C>) {
+        let share = &node.share;
+        let record = RoundRecord {
+            round_num: share.inner.encoded.round_num,
+            start_height: node.round_start,
+            end_height: share.inner.encoded.height,
+            closing_share: share.hash,
+            payouts: share
+                .inner
+                .block
+                .deserialize_rewards()
+                .into_iter()
+                .filter_map(|(script, reward)| {
+                    C::Address::from_script(&script, self.network)
+                        .ok()
+                        .map(|addr| (addr, reward))
+                })
+                .collect(),
+        };
+
+        info!(
+            "Round {} closed by share {}, shares: {}-{}",
+            record.round_num, record.closing_share, record.start_height, record.end_height
+        );
+
+        let mut path = self.rounds_dir.to_path_buf();
+        path.push(record.round_num.to_string());
+        path.set_extension("json");
+        if let Err(e) = fs::write(path, serde_json::to_string_pretty(&record).unwrap()) {
+            warn!("Failed to save round {}: {}", record.round_num, e);
+        }
+    }
+
+    // keeps a share whose parent is unknown and tells which shares to request to connect it
+    fn add_orphan(
+        &self,
+        tree: &ShareTree<C>,
+        block: C::BlockT,
+        hash: U256,
+        parent: U256,
+        height: u32,
+        origin: ShareOrigin,
+    ) -> Result<(ProcessedShare<C>, TipUpdate), ShareVerificationError> {
+        let tip_height = tree.best().share.inner.encoded.height;
+        let parent_height = height.saturating_sub(1);
+
+        // the genesis is always known, and so is everything that can still be reorged to
+        if parent_height == 0 || height.saturating_add(MAX_REORG_DEPTH) <= tip_height {
+            return Err(ShareVerificationError::BadLinkP2P);
+        }
+
+        let inserted = self
+            .orphans
+            .lock()
+            .unwrap()
+            .insert(parent, Orphan { block, hash, origin });
+        if !inserted {
+            return Err(ShareVerificationError::AlreadyKnown);
+        }
+        info!("New orphan share: {}, height: {}", hash, height);
+
+        // on a fork we only know the parent's height, deeper missing ancestors are requested one by one
+        let from_height = parent_height.min(tip_height + 1);
+        let count = (parent_height + 1 - from_height).min(SYNC_PAGE_SIZE as u32) as u8;
+
+        Err(ShareVerificationError::MissingParent { from_height, count })
+    }
+
+    // the orphans waiting for the given share, they should be processed again
+    pub fn take_orphans(&self, parent: &U256) -> Vec<Orphan<C::BlockT>> {
+        self.orphans.lock().unwrap().take_children(parent)
+    }
+
+    // rewinds the window by the disconnected shares (tip first), then replays the connected ones (oldest first)
+    fn move_window(
+        tree: &ShareTree<C>,
+        window: &mut WindowPPLNS<C>,
+        disconnect: &[U256],
+        connect: &[U256],
+    ) {
+        for _ in disconnect {
+            let rewound = window.rewind();
+            debug_assert!(rewound);
+        }
+
+        for hash in connect {
+            window.add(tree.get(hash).unwrap().share.clone());
+        }
+    }
+
+    // replays the shares saved by previous runs on top of the genesis, returns the restored tip height
+    pub fn restore(&self, p2ptarget: &TargetManager, window: &mut WindowPPLNS<C>) -> u32 {
+        let mut height = 1;
+        loop {
+            let block = match self.load_share(height) {
+                Ok(k) => k,
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!("Failed to load share at height {}: {}", height, e);
+                    }
+                    break;
+                }
+            };
+
+            if let Err(e) = self.process_share(block, p2ptarget, window, ShareOrigin::Historic) {
+                warn!("Failed to restore share at height {}: {:?}", height, e);
+                break;
+            }
+            height += 1;
+        }
+
+        let tip = self.p2p_tip();
+        info!(
+            "Restored {} shares from disk, p2p tip: {}, round: {}",
+            tip.inner.encoded.height,
+            tip.hash,
+            self.round_num()
+        );
+        tip.inner.encoded.height
+    }
+
+    // the target for shares on top of the best tip
+    pub fn p2p_target(&self) -> U256 {
+        self.share_tree.lock().unwrap().best().adjustment.target
     }
 
     pub fn round_start_height(&self) -> u32 {
@@ -199,11 +560,12 @@ impl<C: Coin> BlockManager<C> {
         self.main_tip.lock().unwrap()
     }
 
-    pub fn new_block(&self, height: u32, block_hash: &U256) {
+    pub fn new_block(&self, height: u32, block_hash: &U256, target: &U256) {
         self.current_height.store(height, Ordering::Relaxed);
         let mut lock = self.main_tip.lock().unwrap();
         *lock = BlockVerifyContext {
             hash: block_hash.clone(),
+            target: *target,
         };
 
         info!(
@@ -236,7 +598,8 @@ impl<C: Coin> BlockManager<C> {
 
     pub fn load_shares(&self, from_height: u32, count: u8) -> std::io::Result<Vec<C::BlockT>> {
         let mut vec = Vec::new();
-        let to = (from_height + count as u32).min(self.height() + 1);
+        let tip_height = self.p2p_tip().inner.encoded.height;
+        let to = from_height.saturating_add(count as u32).min(tip_height + 1);
         for i in from_height..to {
             vec.push(self.load_share(i)?);
         }
@@ -256,3 +619,81 @@ impl From<EncodeErrorP2P> for ShareVerificationError {
         ShareVerificationError::BadEncoding(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bitcoin::CompactTarget;
+
+    use crate::{
+        coins::bitcoin::MyBtcAddr,
+        p2p::{
+            networking::{
+                hard_config::MAX_FUTURE_SHARE_SECS, messages::ShareVerificationError,
+                utils::time_now_ms,
+            },
+            simulation::{miner, Simulation},
+        },
+        stratum::header::BlockHeader,
+    };
+
+    use super::RoundRecord;
+
+    #[test]
+    fn shares_before_median_time_are_rejected() {
+        let sim = Simulation::new("mtp", 1);
+        for _ in 0..3 {
+            sim.mine(0, &miner(1));
+        }
+
+        // the median of the genesis and the three shares is the second share's time
+        let mut share = sim.share_template(0, &miner(1));
+        share.header.time -= 2;
+        assert_eq!(sim.process(0, share), Err(ShareVerificationError::BadTime));
+
+        let mut share = sim.share_template(0, &miner(1));
+        share.header.time -= 1;
+        assert!(sim.process(0, share).is_ok());
+    }
+
+    #[test]
+    fn shares_from_the_future_are_rejected() {
+        let sim = Simulation::new("future", 1);
+
+        let mut share = sim.share_template(0, &miner(1));
+        share.header.time = (time_now_ms() / 1000 + MAX_FUTURE_SHARE_SECS + 60) as u32;
+        assert_eq!(sim.process(0, share), Err(ShareVerificationError::BadTime));
+    }
+
+    #[test]
+    fn shares_with_other_bits_than_the_main_tip_are_rejected() {
+        let sim = Simulation::new("bits", 1);
+
+        let mut share = sim.share_template(0, &miner(1));
+        share.header.bits = CompactTarget::from_consensus(0x1e0fffff);
+        assert_eq!(sim.process(0, share), Err(ShareVerificationError::BadTarget));
+    }
+
+    #[test]
+    fn shares_meeting_the_main_target_close_the_round() {
+        let sim = Simulation::new("round", 1);
+        let block_manager = &sim.protocol(0).block_manager;
+
+        // about every other share meets regtest's target
+        let closing = (0..32)
+            .map(|_| sim.mine(0, &miner(1)))
+            .find(|_| block_manager.round_num() == 1)
+            .expect("No share met the main target");
+        let tip = block_manager.p2p_tip();
+        assert!(tip.hash <= tip.inner.block.header.get_target());
+        assert_eq!(tip.hash, closing);
+        drop(tip);
+
+        let record: RoundRecord<MyBtcAddr> =
+            serde_json::from_slice(&fs::read(block_manager.rounds_dir.join("0.json")).unwrap())
+                .unwrap();
+        assert_eq!(record.closing_share, closing);
+        assert_eq!(record.end_height, block_manager.p2p_tip().inner.encoded.height);
+    }
+}
@@ -5,9 +5,12 @@ use sha2::{Digest, Sha256};
 use crate::p2p::networking::block::Block;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound = "")]
 // pool hash is the consensus hash
-pub struct ConsensusConfigP2P<BlockT> {
+pub struct ConsensusConfigP2P<BlockT: Block> {
     pub name: String,
+    // pools on different networks never share a chain
+    pub network: BlockT::Network,
     // zero for main pool
     pub parent_pool_hash: U256,
     pub target_1: U256,
@@ -18,7 +21,10 @@ pub struct ConsensusConfigP2P<BlockT> {
     pub block_time_ms: u32,
     pub default_port_p2p: u16,
     pub default_port_stratum: u16,
+    #[serde(default)]
+    pub retarget: RetargetAlgorithm,
 }
+
 // pools that havent submitted shares in a week should be remove from explorable
 // pool target must be easier than the target of its ancestors
 
@@ -26,4 +32,14 @@ impl<BlockT: Block> ConsensusConfigP2P<BlockT> {
     pub fn pool_hash(&self) -> U256 {
         U256::from_le_bytes(Sha256::digest(&bincode::serialize(&self).unwrap()).into())
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RetargetAlgorithm {
+    // clamped retarget every diff_adjust_blocks shares
+    #[default]
+    Windowed,
+    // per share retarget over the last window shares
+    Lwma { window: u32 },
+}
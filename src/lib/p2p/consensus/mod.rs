@@ -1,3 +1,5 @@
 pub mod block_manager;
 pub mod target_manager;
 pub mod consensus;
+pub mod share_tree;
+pub mod orphan_pool;
@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crypto_bigint::U256;
+
+use super::block_manager::ShareOrigin;
+
+pub struct Orphan<B> {
+    pub block: B,
+    pub hash: U256,
+    pub origin: ShareOrigin,
+}
+
+// shares that arrived before their parent, keyed by the parent's hash
+pub struct OrphanPool<B> {
+    by_parent: HashMap<U256, Vec<Orphan<B>>>,
+    // (parent, hash, received) oldest first, for eviction
+    order: VecDeque<(U256, U256, Instant)>,
+    max_size: usize,
+    expiry: Duration,
+}
+
+impl<B> OrphanPool<B> {
+    pub fn new(max_size: usize, expiry: Duration) -> Self {
+        Self {
+            by_parent: HashMap::new(),
+            order: VecDeque::new(),
+            max_size,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn contains(&self, hash: &U256) -> bool {
+        self.order.iter().any(|(_, h, _)| h == hash)
+    }
+
+    // false if the orphan is already known
+    pub fn insert(&mut self, parent: U256, orphan: Orphan<B>) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+
+        if self.contains(&orphan.hash) {
+            return false;
+        }
+
+        self.order.push_back((parent, orphan.hash, now));
+        self.by_parent.entry(parent).or_default().push(orphan);
+
+        while self.order.len() > self.max_size {
+            self.evict_oldest();
+        }
+        true
+    }
+
+    // removes and returns the orphans waiting for the given share
+    pub fn take_children(&mut self, parent: &U256) -> Vec<Orphan<B>> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        if !children.is_empty() {
+            self.order.retain(|(p, _, _)| p != parent);
+        }
+        children
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, _, received)) = self.order.front() {
+            if now.duration_since(*received) < self.expiry {
+                break;
+            }
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let (parent, hash, _) = match self.order.pop_front() {
+            Some(k) => k,
+            None => return,
+        };
+
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|o| o.hash != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crypto_bigint::U256;
+
+    use crate::p2p::consensus::block_manager::ShareOrigin;
+
+    use super::{Orphan, OrphanPool};
+
+    fn orphan(hash: u64) -> Orphan<u64> {
+        Orphan {
+            block: hash,
+            hash: U256::from_u64(hash),
+            origin: ShareOrigin::Live,
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut pool = OrphanPool::new(2, Duration::from_secs(60));
+        let parent = U256::from_u64(100);
+
+        assert!(pool.insert(parent, orphan(1)));
+        assert!(!pool.insert(parent, orphan(1)));
+        assert!(pool.insert(U256::from_u64(200), orphan(2)));
+        assert!(pool.insert(parent, orphan(3)));
+
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&U256::from_u64(1)));
+
+        let children = pool.take_children(&parent);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].block, 3);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn expires_old_orphans() {
+        let mut pool = OrphanPool::new(10, Duration::ZERO);
+
+        pool.insert(U256::from_u64(100), orphan(1));
+        pool.insert(U256::from_u64(100), orphan(2));
+
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&U256::from_u64(2)));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use crypto_bigint::U256;
+
+use crate::coins::coin::Coin;
+
+use super::block_manager::ProcessedShare;
+use super::target_manager::Adjustment;
+
+pub struct ShareNode<C: Coin> {
+    pub share: ProcessedShare<C>,
+    // work required by the targets from genesis up to and including this share, the heaviest chain wins
+    pub chain_work: u128,
+    // the target the share's children must meet
+    pub adjustment: Adjustment,
+    // height of the first share of the share's round
+    pub round_start: u32,
+}
+
+// every known valid share that's recent enough to be reorged to, keyed by hash
+pub struct ShareTree<C: Coin> {
+    nodes: HashMap<U256, ShareNode<C>>,
+    best: U256,
+}
+
+impl<C: Coin> ShareTree<C> {
+    pub fn new(genesis: ProcessedShare<C>, adjustment: Adjustment) -> Self {
+        let best = genesis.hash;
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            best,
+            ShareNode {
+                share: genesis,
+                chain_work: 0,
+                adjustment,
+                round_start: 0,
+            },
+        );
+
+        Self { nodes, best }
+    }
+
+    pub fn get(&self, hash: &U256) -> Option<&ShareNode<C>> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &U256) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn best(&self) -> &ShareNode<C> {
+        &self.nodes[&self.best]
+    }
+
+    pub fn best_hash(&self) -> U256 {
+        self.best
+    }
+
+    // the parent must already be in the tree, work is what the share's target required
+    pub fn insert(
+        &mut self,
+        share: ProcessedShare<C>,
+        work: u128,
+        adjustment: Adjustment,
+        round_start: u32,
+    ) -> &ShareNode<C> {
+        let chain_work = self.nodes[&share.inner.encoded.prev_hash]
+            .chain_work
+            .saturating_add(work);
+        let hash = share.hash;
+
+        self.nodes.insert(
+            hash,
+            ShareNode {
+                share,
+                chain_work,
+                adjustment,
+                round_start,
+            },
+        );
+        &self.nodes[&hash]
+    }
+
+    // heaviest chain wins, on a tie the first seen is kept
+    pub fn outweighs_best(&self, hash: &U256) -> bool {
+        self.nodes[hash].chain_work > self.best().chain_work
+    }
+
+    pub fn set_best(&mut self, hash: U256) {
+        debug_assert!(self.nodes.contains_key(&hash));
+        self.best = hash;
+    }
+
+    // the shares to disconnect (tip first) and to connect (oldest first) to move from one share to another,
+    // None if the common ancestor has already been pruned
+    pub fn path(&self, from: &U256, to: &U256) -> Option<(Vec<U256>, Vec<U256>)> {
+        let mut disconnect = Vec::new();
+        let mut connect = Vec::new();
+
+        let mut from = self.nodes.get(from)?;
+        let mut to = self.nodes.get(to)?;
+
+        while from.share.hash != to.share.hash {
+            let from_height = from.share.inner.encoded.height;
+            let to_height = to.share.inner.encoded.height;
+
+            if from_height >= to_height {
+                disconnect.push(from.share.hash);
+                from = self.nodes.get(&from.share.inner.encoded.prev_hash)?;
+            }
+            if to_height >= from_height {
+                connect.push(to.share.hash);
+                to = self.nodes.get(&to.share.inner.encoded.prev_hash)?;
+            }
+        }
+
+        connect.reverse();
+        Some((disconnect, connect))
+    }
+
+    // walks back the chain from the given share, inclusive
+    pub fn ancestors<'a>(&'a self, from: &U256) -> impl Iterator<Item = &'a ShareNode<C>> + 'a {
+        std::iter::successors(self.nodes.get(from), |node| {
+            self.nodes.get(&node.share.inner.encoded.prev_hash)
+        })
+    }
+
+    // forget shares that are too old to be reorged to
+    pub fn prune(&mut self, min_height: u32) {
+        let best = self.best;
+        self.nodes
+            .retain(|hash, node| *hash == best || node.share.inner.encoded.height >= min_height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+    use crypto_bigint::U256;
+
+    use crate::{
+        coins::bitcoin::Btc,
+        p2p::{
+            consensus::{block_manager::ProcessedShare, target_manager::Adjustment},
+            networking::{
+                pplns::ScoreChanges,
+                share::{CoinbaseEncodedP2P, ShareP2P},
+            },
+        },
+    };
+
+    use super::ShareTree;
+
+    fn share(hash: u64, prev: u64, height: u32) -> ProcessedShare<Btc> {
+        ProcessedShare {
+            inner: ShareP2P {
+                block: bitcoin::blockdata::constants::genesis_block(Network::Regtest),
+                encoded: CoinbaseEncodedP2P {
+                    prev_hash: U256::from_u64(prev),
+                    height,
+                    round_num: 0,
+                },
+                score_changes: ScoreChanges {
+                    added: Vec::new(),
+                    removed: Vec::new(),
+                },
+            },
+            hash: U256::from_u64(hash),
+            score: 0,
+        }
+    }
+
+    fn adjustment() -> Adjustment {
+        Adjustment {
+            time: 0,
+            height: 0,
+            target: U256::MAX,
+        }
+    }
+
+    // genesis 0 -> 1 -> 2 -> 3 and a fork 1 -> 12 -> 13
+    fn forked_tree() -> ShareTree<Btc> {
+        let mut tree = ShareTree::new(share(0, 0, 0), adjustment());
+        for (hash, prev, height) in [(1, 0, 1), (2, 1, 2), (3, 2, 3), (12, 1, 2), (13, 12, 3)] {
+            tree.insert(share(hash, prev, height), 1, adjustment(), 0);
+        }
+        tree
+    }
+
+    #[test]
+    fn path_crosses_forks() {
+        let tree = forked_tree();
+        let (disconnect, connect) = tree.path(&U256::from_u64(3), &U256::from_u64(13)).unwrap();
+        assert_eq!(disconnect, vec![U256::from_u64(3), U256::from_u64(2)]);
+        assert_eq!(connect, vec![U256::from_u64(12), U256::from_u64(13)]);
+
+        // a share's own ancestor is reached by disconnecting only
+        let (disconnect, connect) = tree.path(&U256::from_u64(3), &U256::from_u64(1)).unwrap();
+        assert_eq!(disconnect, vec![U256::from_u64(3), U256::from_u64(2)]);
+        assert!(connect.is_empty());
+
+        assert!(tree.path(&U256::from_u64(3), &U256::from_u64(99)).is_none());
+    }
+
+    #[test]
+    fn tie_keeps_first_seen() {
+        let mut tree = forked_tree();
+        tree.set_best(U256::from_u64(3));
+
+        // the fork has as much work as the best chain
+        assert!(!tree.outweighs_best(&U256::from_u64(13)));
+
+        tree.insert(share(14, 13, 4), 1, adjustment(), 0);
+        assert!(tree.outweighs_best(&U256::from_u64(14)));
+    }
+
+    #[test]
+    fn prune_forgets_old_shares_but_the_best() {
+        let mut tree = forked_tree();
+        tree.set_best(U256::from_u64(13));
+        tree.prune(3);
+
+        assert!(!tree.contains(&U256::from_u64(1)));
+        assert!(!tree.contains(&U256::from_u64(12)));
+        assert!(tree.contains(&U256::from_u64(3)));
+        assert_eq!(tree.best_hash(), U256::from_u64(13));
+
+        // the common ancestor is gone, the fork can't be reorged to anymore
+        assert!(tree.path(&U256::from_u64(13), &U256::from_u64(3)).is_none());
+    }
+}
@@ -9,24 +9,26 @@ use crate::{
     stratum::header::BlockHeader,
 };
 
-use super::consensus::ConsensusConfigP2P;
+use super::consensus::{ConsensusConfigP2P, RetargetAlgorithm};
 
-
-struct Adjustment {
-    time: u32,
-    height: u32,
-    target: U256,
+// the target in effect from a share onwards, every share chain node carries one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adjustment {
+    pub time: u32,
+    pub height: u32,
+    pub target: U256,
 }
 
+// the retarget schedule, the same for every node
 pub struct TargetManager {
-    last_adjustment: Adjustment,
+    genesis_adjustment: Adjustment,
     target_time: Duration,
-    // once in per how many mainnet blocks to readjust pool diff.
+    // once in per how many shares to readjust pool diff.
     diff_adjust_blocks: u32,
+    algorithm: RetargetAlgorithm,
 }
 
 // start each pool difficulty with the genesis block difficulty
-// TODO: save pool start time and block maybe
 impl TargetManager {
     pub fn new<C: Coin>(config: &ConsensusConfigP2P<C::BlockT>, target_time: Duration, diff_adjust: u32) -> Self {
         // let target = genesis_block.get_header().get_target();
@@ -39,29 +41,95 @@ impl TargetManager {
         assert!(&target <= &MAX_TARGET);
 
         Self {
-            last_adjustment: Adjustment {
+            genesis_adjustment: Adjustment {
                 time: config.genesis_block.get_header().get_time(),
                 target,
                 height: 0,
             },
             target_time,
             diff_adjust_blocks: diff_adjust,
+            algorithm: config.retarget,
+        }
+    }
+
+    // how many ancestors of a share (parent first) are needed to compute its adjustment
+    pub fn history_len(&self) -> u32 {
+        match self.algorithm {
+            RetargetAlgorithm::Windowed => 0,
+            RetargetAlgorithm::Lwma { window } => window,
         }
     }
 
-    pub fn target(&self) -> &U256 {
-        &self.last_adjustment.target
+    pub fn genesis_adjustment(&self) -> Adjustment {
+        self.genesis_adjustment.clone()
     }
 
-    pub fn adjust<C: Coin>(&mut self, current_height: u32, block: &C::BlockT) {
-        if current_height - self.last_adjustment.height < self.diff_adjust_blocks {
-            return;
+    // the adjustment in effect after a share at the given height and time, whose parent was under last.
+    // history is (time, adjustment target) of the share's ancestors, parent first, up to history_len of them
+    pub fn next_adjustment(
+        &self,
+        last: &Adjustment,
+        history: &[(u32, U256)],
+        current_height: u32,
+        current_time: u32,
+    ) -> Adjustment {
+        match self.algorithm {
+            RetargetAlgorithm::Windowed => self.windowed(last, current_height, current_time),
+            RetargetAlgorithm::Lwma { .. } => self.lwma(last, history, current_height, current_time),
+        }
+    }
+
+    // linearly weighted moving average, retargets every share giving the recent solve times the most weight
+    fn lwma(&self, last: &Adjustment, history: &[(u32, U256)], current_height: u32, current_time: u32) -> Adjustment {
+        if history.is_empty() {
+            return last.clone();
         }
 
-        let current_time = block.get_header().get_time();
-        let current_target = self.last_adjustment.target;
-        let passed_secs =
-            std::cmp::max(1, current_time as i64 - self.last_adjustment.time as i64) as u64;
+        // (solve time, target it was solved under), oldest first
+        let mut solves = Vec::with_capacity(history.len());
+        let mut solved_at = current_time;
+        for (time, target) in history {
+            solves.push((solved_at as i64 - *time as i64, *target));
+            solved_at = *time;
+        }
+        solves.reverse();
+
+        let target_ms = self.target_time.as_millis() as i64;
+        let n = solves.len() as i64;
+        // sum of weights * target time
+        let k = n * (n + 1) / 2 * target_ms;
+
+        let mut weighted_ms = 0i64;
+        let mut target_sum = U256::ZERO;
+        for (i, (solve_secs, target)) in solves.iter().enumerate() {
+            // keep out of order timestamps from swinging the target too hard
+            let solve_ms = (solve_secs * 1000).clamp(-6 * target_ms, 6 * target_ms);
+            weighted_ms += (i as i64 + 1) * solve_ms;
+            target_sum = target_sum.wrapping_add(&target.wrapping_div(&U256::from(n as u64)));
+        }
+        let weighted_ms = std::cmp::max(weighted_ms, k / 10) as u64;
+
+        // avg target * weighted solve time / k, dividing first so it can't overflow
+        let new_target = target_sum
+            .wrapping_div(&U256::from(k as u64))
+            .checked_mul(&U256::from(weighted_ms))
+            .unwrap_or(MAX_TARGET)
+            .min(MAX_TARGET);
+
+        Adjustment {
+            time: current_time,
+            height: current_height,
+            target: new_target,
+        }
+    }
+
+    fn windowed(&self, last: &Adjustment, current_height: u32, current_time: u32) -> Adjustment {
+        if current_height.saturating_sub(last.height) < self.diff_adjust_blocks {
+            return last.clone();
+        }
+
+        let current_target = last.target;
+        let passed_secs = std::cmp::max(1, current_time as i64 - last.time as i64) as u64;
 
         info!("Current target: {}", current_target);
 
@@ -89,12 +157,119 @@ impl TargetManager {
                 .checked_mul(&U256::from(passed_ms))
                 .unwrap_or(current_target);
         }
+
+        if new_target > MAX_TARGET {
+            new_target = MAX_TARGET;
+        }
         info!("New target: {}, time: {}", new_target, current_time);
 
-        self.last_adjustment = Adjustment {
+        Adjustment {
             time: current_time,
             height: current_height,
             target: new_target,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crypto_bigint::U256;
+
+    use super::{Adjustment, TargetManager};
+    use crate::p2p::consensus::consensus::RetargetAlgorithm;
+
+    const BLOCK_TIME_SECS: f64 = 10.0;
+
+    fn initial_target() -> U256 {
+        U256::ONE.shl_vartime(200)
+    }
+
+    fn manager(algorithm: RetargetAlgorithm) -> TargetManager {
+        TargetManager {
+            genesis_adjustment: Adjustment {
+                time: 0,
+                height: 0,
+                target: initial_target(),
+            },
+            target_time: Duration::from_secs(BLOCK_TIME_SECS as u64),
+            diff_adjust_blocks: 16,
+            algorithm,
+        }
+    }
+
+    // how many times harder than the initial target
+    fn difficulty(target: &U256) -> f64 {
+        let scaled = |t: &U256| t.shr_vartime(140).as_words()[0] as f64;
+        scaled(&initial_target()) / scaled(target)
+    }
+
+    // expected solve time of every share, a hashrate of 1 solves the initial target in the block time
+    fn simulate(manager: &TargetManager, hashrate: impl Fn(u32) -> f64, shares: u32) -> Vec<f64> {
+        let mut clock = 0.0;
+        // (time, adjustment target), newest last
+        let mut history = vec![(0u32, initial_target())];
+        let mut last = manager.genesis_adjustment();
+        let mut solve_times = Vec::new();
+
+        for height in 1..=shares {
+            let solve = BLOCK_TIME_SECS * difficulty(&last.target) / hashrate(height);
+            clock += solve;
+            solve_times.push(solve);
+
+            let ancestors: Vec<(u32, U256)> = history
+                .iter()
+                .rev()
+                .take(manager.history_len() as usize)
+                .cloned()
+                .collect();
+            last = manager.next_adjustment(&last, &ancestors, height, clock as u32);
+            history.push((clock as u32, last.target));
+        }
+        solve_times
+    }
+
+    fn average(solve_times: &[f64]) -> f64 {
+        solve_times.iter().sum::<f64>() / solve_times.len() as f64
+    }
+
+    const LWMA: RetargetAlgorithm = RetargetAlgorithm::Lwma { window: 20 };
+
+    #[test]
+    fn stable_hashrate() {
+        for algorithm in [RetargetAlgorithm::Windowed, LWMA] {
+            let solve_times = simulate(&manager(algorithm), |_| 1.0, 300);
+            let avg = average(&solve_times[200..]);
+
+            assert!((avg - BLOCK_TIME_SECS).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn hashrate_step() {
+        let hashrate = |height| if height <= 100 { 1.0 } else { 8.0 };
+
+        let windowed = simulate(&manager(RetargetAlgorithm::Windowed), hashrate, 400);
+        let lwma = simulate(&manager(LWMA), hashrate, 400);
+        // both settle, lwma recovers from the jump faster
+        assert!((average(&windowed[300..]) - BLOCK_TIME_SECS).abs() < 1.0);
+        assert!((average(&lwma[300..]) - BLOCK_TIME_SECS).abs() < 1.0);
+        assert!(
+            (average(&lwma[100..125]) - BLOCK_TIME_SECS).abs()
+                < (average(&windowed[100..125]) - BLOCK_TIME_SECS).abs()
+        );
+    }
+
+    #[test]
+    fn oscillating_hashrate() {
+        let hashrate = |height: u32| 2.0 + (height as f64 / 20.0).sin();
+
+        for algorithm in [RetargetAlgorithm::Windowed, LWMA] {
+            let solve_times = simulate(&manager(algorithm), hashrate, 1000);
+            let avg = average(&solve_times[100..]);
+
+            assert!((avg - BLOCK_TIME_SECS).abs() < 2.0);
+        }
+    }
+}
@@ -1,23 +1,71 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 
 use crypto_bigint::U256;
 
-#[derive(Debug, Default)]
-pub struct DuplicateHashChecker(HashSet<u64>);
+// remembers only the latest hashes, older ones are forgotten so it can't grow forever
+#[derive(Debug)]
+pub struct DuplicateHashChecker {
+    seen: HashSet<U256>,
+    order: VecDeque<U256>,
+    capacity: usize,
+}
 
 impl DuplicateHashChecker {
-    fn short_hash(hash: &U256) -> u64 {
-        hash.as_words()[0]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
     }
 
     pub fn did_contain(&mut self, hash: &U256) -> bool {
-        let short = Self::short_hash(hash);
-        let res = self.0.contains(&short);
+        let res = self.contains(hash);
 
         if !res {
-            self.0.insert(short);
+            self.insert(hash);
         }
 
         res
     }
-}
\ No newline at end of file
+
+    pub fn contains(&self, hash: &U256) -> bool {
+        self.seen.contains(hash)
+    }
+
+    pub fn insert(&mut self, hash: &U256) {
+        if !self.seen.insert(*hash) {
+            return;
+        }
+
+        self.order.push_back(*hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto_bigint::U256;
+
+    use super::DuplicateHashChecker;
+
+    #[test]
+    fn forgets_the_oldest_hashes() {
+        let mut checker = DuplicateHashChecker::new(2);
+        assert!(!checker.did_contain(&U256::from_u64(1)));
+        assert!(checker.did_contain(&U256::from_u64(1)));
+
+        checker.insert(&U256::from_u64(2));
+        checker.insert(&U256::from_u64(3));
+        assert!(!checker.contains(&U256::from_u64(1)));
+        assert!(checker.contains(&U256::from_u64(3)));
+
+        // hashes sharing their low word are still told apart
+        let high = U256::from_u64(3).shl_vartime(128);
+        assert!(!checker.contains(&high.wrapping_add(&U256::from_u64(3))));
+    }
+}
@@ -2,3 +2,5 @@ pub mod networking;
 pub mod consensus;
 pub mod duplicate_checker;
 
+#[cfg(test)]
+mod simulation;
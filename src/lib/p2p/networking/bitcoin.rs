@@ -1,8 +1,12 @@
 use bitcoin::absolute::LockTime;
 use bitcoin::hashes::Hash;
-use bitcoin::merkle_tree::calculate_root_inline;
+use bitcoin::hash_types::WitnessMerkleNode;
+use bitcoin::merkle_tree::{calculate_root, calculate_root_inline};
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::script::{read_scriptint, Instruction};
 
-use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Witness};
+use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Witness, Wtxid};
 use bitcoincore_rpc::bitcoin::block::Version;
 
 use bitcoincore_rpc::bitcoin::hash_types::TxMerkleNode;
@@ -12,8 +16,6 @@ use bitcoincore_rpc::bitcoincore_rpc_json::GetBlockTemplateResult;
 use itertools::Itertools;
 use log::warn;
 
-use crate::coins::bitcoin::Btc;
-
 use super::block::{Block, EncodeErrorP2P};
 use super::hard_config::GENERATION_GRAFFITI;
 use super::pplns::get_reward;
@@ -22,6 +24,9 @@ use super::share::CoinbaseEncodedP2P;
 //     o1.value == o2.value && o1.script_pubkey == o2.script_pubkey
 // }
 
+const WITNESS_RESERVED_VALUE: [u8; 32] = [0u8; 32];
+const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
 pub const SCRIPTLESS_COINB1_SIZE: usize = 4 + 1 /* one input */+ 32 + 4;
 // pub const MIN_SCRIPT_SIZE: usize = 4 /* height bytes amount will remain same for 300 years */ + 1 + GENERATION_GRAFFITI.len() + std::mem::size_of::<CoinabseEncodedP2P>() +1 /* push nonce */;
 
@@ -29,6 +34,7 @@ impl Block for bitcoin::block::Block {
     type HeaderT = bitcoin::block::Header;
     type BlockTemplateT = GetBlockTemplateResult;
     type Script = ScriptBuf;
+    type Network = Network;
 
     fn from_block_template(
         template: &GetBlockTemplateResult,
@@ -74,6 +80,11 @@ impl Block for bitcoin::block::Block {
             }
         }
 
+        // segwit blocks must commit to the witnesses of their transactions
+        if !template.default_witness_commitment.is_empty() {
+            add_witness_commitment(&mut txs);
+        }
+
         let tx_hashes = txs.iter().map(|t| t.txid()).collect_vec();
         let merkle_root = calculate_root_inline(&mut tx_hashes.clone()).unwrap();
 
@@ -106,26 +117,39 @@ impl Block for bitcoin::block::Block {
         &self.header
     }
     // mainnet consensus
-    fn verify_main_consensus(&self, check_height: u32) -> bool {
+    fn verify_main_consensus(&self, check_height: Option<u32>, network: Network) -> bool {
         let gen_tx = &self.txdata[0];
         let gen_input = &gen_tx.input[0];
 
-        if !self.check_merkle_root() {
+        if !self.check_merkle_root() || !self.check_witness_commitment() {
             return false;
         }
 
-        // regtest doesnt encode height
-        if Btc::NETWORK != Network::Regtest {
-            let height_script = ScriptBuf::builder()
-                .push_int(check_height as i64)
-                .into_script();
-
-            gen_input
-                .script_sig
-                .as_bytes()
-                .starts_with(height_script.as_bytes())
-        } else {
-            true
+        match check_height {
+            // regtest doesnt encode height
+            Some(check_height) if network != Network::Regtest => {
+                let height_script = ScriptBuf::builder()
+                    .push_int(check_height as i64)
+                    .into_script();
+
+                gen_input
+                    .script_sig
+                    .as_bytes()
+                    .starts_with(height_script.as_bytes())
+            }
+            _ => true,
+        }
+    }
+
+    fn get_main_height(&self) -> Option<u32> {
+        let gen_input = self.txdata.first()?.input.first()?;
+        match gen_input.script_sig.instructions_minimal().next()?.ok()? {
+            Instruction::PushBytes(bytes) => read_scriptint(bytes.as_bytes()).ok()?.try_into().ok(),
+            // heights up to 16 are pushed as a single opcode
+            Instruction::Op(op) => match op.classify(ClassifyContext::Legacy) {
+                Class::PushNum(n) => n.try_into().ok(),
+                _ => None,
+            },
         }
     }
 
@@ -136,6 +160,11 @@ impl Block for bitcoin::block::Block {
         let mut res = Vec::with_capacity(gen_outs.len());
 
         for out in gen_outs {
+            // the witness commitment
+            if out.script_pubkey.is_op_return() {
+                continue;
+            }
+
             let val = out.value.to_sat();
             res.push((out.script_pubkey.clone(), val));
         }
@@ -177,6 +206,29 @@ impl From<bitcoin::address::Error> for EncodeErrorP2P {
     }
 }
 
+// the coinbase commits to the witness merkle root (with its own wtxid as zero) and the reserved value
+fn add_witness_commitment(txs: &mut [Transaction]) {
+    let wtxids = std::iter::once(Wtxid::all_zeros().to_raw_hash())
+        .chain(txs.iter().skip(1).map(|t| t.wtxid().to_raw_hash()));
+    let witness_root = WitnessMerkleNode::from_raw_hash(calculate_root(wtxids).unwrap());
+    let commitment =
+        bitcoin::Block::compute_witness_commitment(&witness_root, &WITNESS_RESERVED_VALUE);
+
+    let mut commitment_push = [0u8; 36];
+    commitment_push[..4].copy_from_slice(&WITNESS_COMMITMENT_HEADER);
+    commitment_push[4..].copy_from_slice(commitment.as_byte_array());
+
+    let coinbase = &mut txs[0];
+    coinbase.input[0].witness = Witness::from_slice(&[WITNESS_RESERVED_VALUE]);
+    coinbase.output.push(TxOut {
+        value: bitcoin::Amount::ZERO,
+        script_pubkey: ScriptBuf::builder()
+            .push_opcode(OP_RETURN)
+            .push_slice(commitment_push)
+            .into_script(),
+    });
+}
+
 fn generate_bitcoin_script(main_height: u64, encode: CoinbaseEncodedP2P) -> ScriptBuf {
     ScriptBuf::builder()
         .push_int(main_height as i64)
@@ -196,13 +248,56 @@ pub mod tests {
         coins::bitcoin::Btc,
         p2p::{networking::{
             block::Block,
-            pplns::{ScoreChanges, WindowPPLNS},
+            pplns::{self, ScoreChanges, WindowPPLNS},
              share::{ShareP2P, CoinbaseEncodedP2P},
         }, consensus::{block_manager::{ProcessedShare, BlockManager}, target_manager::TargetManager}},
         stratum::header::BlockHeader,
     };
+    use bitcoin::{Network, ScriptBuf, Witness};
+    use bitcoincore_rpc::bitcoincore_rpc_json::GetBlockTemplateResult;
     use pretty_assertions::assert_eq;
 
+    pub fn segwit_template() -> GetBlockTemplateResult {
+        serde_json::from_str(include_str!(
+            "../../../../test_data/segwit_block_template.json"
+        ))
+        .unwrap()
+    }
+
+    pub fn sample_payout_script() -> ScriptBuf {
+        ScriptBuf::from_hex("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap()
+    }
+
+    #[test]
+    fn segwit_template_witness_commitment() {
+        let template = segwit_template();
+        let (mut block, _) = bitcoin::Block::from_block_template(
+            &template,
+            [(sample_payout_script(), pplns::MAX_SCORE)].into_iter(),
+            CoinbaseEncodedP2P::default(),
+        );
+
+        // same commitment bitcoind would have put there
+        let coinbase = &block.txdata[0];
+        assert_eq!(
+            coinbase.output.last().unwrap().script_pubkey,
+            template.default_witness_commitment
+        );
+        assert_eq!(coinbase.input[0].witness.len(), 1);
+        assert!(block.verify_main_consensus(None, Network::Regtest));
+
+        // the commitment isn't a payout
+        assert_eq!(
+            block.deserialize_rewards(),
+            vec![(sample_payout_script(), template.coinbase_value.to_sat())]
+        );
+
+        // witnesses aren't covered by the merkle root, only by the commitment
+        block.txdata[1].input[0].witness = Witness::new();
+        assert!(block.check_merkle_root());
+        assert!(!block.verify_main_consensus(None, Network::Regtest));
+    }
+
     // #[test]
     // fn serialize_first_share_p2p() {}
 
@@ -22,6 +22,8 @@ pub trait Block:
     type HeaderT: BlockHeader;
     type BlockTemplateT;
     type Script: Send + Sync + PartialEq + Eq + Hash + Clone;
+    // the chain the blocks belong to, known at runtime
+    type Network: Copy + PartialEq + std::fmt::Debug + Serialize + DeserializeOwned + Send + Sync;
 
     fn get_header_mut(&mut self) -> &mut Self::HeaderT;
     fn get_header(&self) -> &Self::HeaderT;
@@ -33,7 +35,10 @@ pub trait Block:
     fn deserialize_rewards(&self) -> Vec<(Self::Script, u64)>;
         
     fn deserialize_p2p_encoded(&self) -> Result<CoinbaseEncodedP2P, EncodeErrorP2P>;
-    fn verify_main_consensus(&self, height: u32) -> bool;
+    // height is only known (and checked) for shares mined on the current main tip
+    fn verify_main_consensus(&self, height: Option<u32>, network: Self::Network) -> bool;
+    // the main chain height the coinbase commits to
+    fn get_main_height(&self) -> Option<u32>;
 
     fn get_coinbase_outs(&self) -> u64;
 }
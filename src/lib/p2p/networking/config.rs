@@ -5,15 +5,18 @@ use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 
 
-use crate::p2p::consensus::consensus::ConsensusConfigP2P;
+use crate::{p2p::consensus::consensus::ConsensusConfigP2P, stratum::job_fetcher::DaemonAuth};
 
 use super::block::Block;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ConfigP2P<BlockT> {
+#[serde(bound = "")]
+pub struct ConfigP2P<BlockT: Block> {
     pub consensus: ConsensusConfigP2P<BlockT>,
     pub max_peer_connections: u32,
     pub rpc_url: String,
+    #[serde(default)]
+    pub rpc_auth: DaemonAuth,
     // #[serde(flatten)]
     pub data_dir: Box<Path>,
     // needs to be aware of his own listening for the protocol
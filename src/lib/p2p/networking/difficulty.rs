@@ -28,6 +28,16 @@ pub fn get_diff_score(hash: &U256, diff1: &U256) -> Score {
     )
 }
 
+// the expected hashes to meet the target, what the share chain's weight is made of
+pub fn get_target_work(target: &U256) -> u128 {
+    let work = U256::MAX.wrapping_div(&target.saturating_add(&U256::ONE));
+    let words = work.as_words();
+    if words[2] != 0 || words[3] != 0 {
+        return u128::MAX;
+    }
+    (words[1] as u128) << 64 | words[0] as u128
+}
+
 pub fn get_target_from_diff_units(diff_millis: u64, diff1: &U256) -> U256 {
     diff1
         .checked_mul(&PPLNS_SHARE_UNITS_256)
@@ -39,7 +49,7 @@ pub fn get_target_from_diff_units(diff_millis: u64, diff1: &U256) -> U256 {
 mod tests {
     use crypto_bigint::U256;
 
-    use crate::p2p::networking::difficulty::get_diff_score;
+    use crate::p2p::networking::difficulty::{get_diff_score, get_target_work};
 
     pub static DIFF1: U256 =
         U256::from_be_hex("00000000FFFF0000000000000000000000000000000000000000000000000000");
@@ -55,4 +65,12 @@ mod tests {
         let result = get_diff_score(&check, &DIFF1);
         assert_eq!(result, 1818648 /* 536145414 */);
     }
+
+    #[test]
+    fn work_follows_target() {
+        let easy = U256::MAX.shr_vartime(32);
+        assert_eq!(get_target_work(&easy), (1 << 32) - 1);
+        assert_eq!(get_target_work(&easy.shr_vartime(1)), (1 << 33) - 1);
+        assert_eq!(get_target_work(&U256::ZERO), u128::MAX);
+    }
 }
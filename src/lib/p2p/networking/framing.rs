@@ -0,0 +1,110 @@
+use crate::{
+    framing::{FrameError, Framing},
+    stratum::job::sha256d,
+};
+
+use super::hard_config::{MAX_P2P_FRAME_SIZE, P2P_MAGIC};
+
+// magic | payload length (u32 le) | checksum (first 4 bytes of sha256d(payload)) | payload
+pub const HEADER_SIZE: usize = 4 + 4 + 4;
+
+pub struct FramingP2P;
+
+impl FramingP2P {
+    fn checksum(payload: &[u8]) -> [u8; 4] {
+        sha256d(payload)[..4].try_into().unwrap()
+    }
+}
+
+impl Framing for FramingP2P {
+    fn decode(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        if buf.len() < HEADER_SIZE {
+            // check magic as soon as we can to drop garbage early
+            if !P2P_MAGIC.starts_with(&buf[..buf.len().min(P2P_MAGIC.len())]) {
+                return Err(FrameError::BadMagic);
+            }
+            return Ok(None);
+        }
+
+        if buf[..4] != P2P_MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+
+        let len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        if len > MAX_P2P_FRAME_SIZE {
+            return Err(FrameError::TooLarge(len));
+        }
+
+        if buf.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let checksum: [u8; 4] = buf[8..12].try_into().unwrap();
+        let payload = buf[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        buf.drain(..HEADER_SIZE + len);
+
+        if checksum != Self::checksum(&payload) {
+            return Err(FrameError::BadChecksum);
+        }
+
+        Ok(Some(payload))
+    }
+
+    fn encode(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(&P2P_MAGIC);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&Self::checksum(payload));
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        framing::{FrameError, Framing},
+        p2p::networking::hard_config::MAX_P2P_FRAME_SIZE,
+    };
+
+    use super::{FramingP2P, HEADER_SIZE};
+
+    #[test]
+    fn roundtrip_with_newlines() {
+        let payload = vec![b'\n', 0, 0x0A, 0xff, b'\n'];
+        let mut buf = FramingP2P::encode(&payload);
+        buf.extend_from_slice(&FramingP2P::encode(&[]));
+
+        assert_eq!(FramingP2P::decode(&mut buf), Ok(Some(payload)));
+        assert_eq!(FramingP2P::decode(&mut buf), Ok(Some(Vec::new())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_frame() {
+        let frame = FramingP2P::encode(b"share");
+        let mut buf = frame[..HEADER_SIZE + 2].to_vec();
+
+        assert_eq!(FramingP2P::decode(&mut buf), Ok(None));
+        buf.extend_from_slice(&frame[HEADER_SIZE + 2..]);
+        assert_eq!(FramingP2P::decode(&mut buf), Ok(Some(b"share".to_vec())));
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut buf = b"{\"id\"".to_vec();
+        assert_eq!(FramingP2P::decode(&mut buf), Err(FrameError::BadMagic));
+
+        let mut buf = FramingP2P::encode(b"share");
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert_eq!(FramingP2P::decode(&mut buf), Err(FrameError::BadChecksum));
+
+        let mut buf = FramingP2P::encode(b"share");
+        buf[4..8].copy_from_slice(&(MAX_P2P_FRAME_SIZE as u32 + 1).to_le_bytes());
+        assert_eq!(
+            FramingP2P::decode(&mut buf),
+            Err(FrameError::TooLarge(MAX_P2P_FRAME_SIZE + 1))
+        );
+    }
+}
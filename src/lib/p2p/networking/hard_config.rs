@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crypto_bigint::U256;
 
 pub const CURRENT_VERSION: u32 = 1;
@@ -5,6 +7,15 @@ pub const OLDEST_COMPATIBLE_VERSION: u32 = 1;
 
 pub const DEFAULT_STRATUM_CREATE_POOL_PORT: u16 = 9999;
 
+// every p2p frame starts with these
+pub const P2P_MAGIC: [u8; 4] = *b"SKP2";
+// a full page of shares (255 blocks) has to fit
+pub const MAX_P2P_FRAME_SIZE: usize = 64 * 1024 * 1024;
+// how many shares to request at once when syncing
+pub const SYNC_PAGE_SIZE: u8 = 64;
+// how long to wait for a peer to answer during sync before continuing from the local tip
+pub const SYNC_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
 // one share is this many share units (SUI) :)
 // this is the lowest payout value: coin / units
 const PAYOUT_DECIMAL_PERCISION: u32 = 6;
@@ -13,9 +24,20 @@ pub const PPLNS_SHARE_UNITS_256: U256 = U256::from_u64(PPLNS_SHARE_UNITS);
 
 pub const PPLNS_DIFF_MULTIPLIER: u64 = 5;
 pub const MAX_RETARGET_FACTOR : u64 = 2;
+// shares deeper than this below the best tip are final
+pub const MAX_REORG_DEPTH: u32 = 64;
+// shares waiting for their parent
+pub const MAX_ORPHAN_SHARES: usize = 256;
+pub const ORPHAN_EXPIRY_SECS: u64 = 10 * 60;
+// a share's time must be after the median of this many ancestors, like bitcoin's median time past
+pub const MEDIAN_TIME_SHARES: usize = 11;
+pub const MAX_FUTURE_SHARE_SECS: u64 = 5 * 60;
+// relayed shares remembered so they aren't relayed again, a few reorg depths of them
+pub const SEEN_SHARES_CAPACITY: usize = 4 * MAX_REORG_DEPTH as usize;
 
-// pub const DEV_ADDRESS_BTC_STR: &str = "bc1q3k7q92qf3hmpdpekz4t9r2e3tszy2g4gv9gwea";
-pub const DEV_ADDRESS_BTC_STR: &'static str = "bcrt1q9ude4m7uetjdwv5ud5h6qn7740ret7sznanxch";
+pub const DEV_ADDRESS_BTC_STR: &'static str = "bc1q3k7q92qf3hmpdpekz4t9r2e3tszy2g4gv9gwea";
+// used on testnet, signet and regtest
+pub const DEV_ADDRESS_BTC_TEST_STR: &str = "bcrt1q9ude4m7uetjdwv5ud5h6qn7740ret7sznanxch";
 
 // graffiti term borrowed from iron fish (?), very nice.
 pub const GENERATION_GRAFFITI : &'static [u8; 32] = b"Mined the right way on P3Pool ||";
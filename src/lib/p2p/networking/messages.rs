@@ -12,7 +12,8 @@ use super::{
 // node needs to know and verify where the current window started
 
 #[derive(Serialize, Deserialize, Debug)]
-pub enum Messages<BlockT> {
+#[serde(bound = "")]
+pub enum Messages<BlockT: Block> {
     Reject,
 
     Hello(Hello),
@@ -34,7 +35,8 @@ pub enum Messages<BlockT> {
     },
 
     // provide default port for each pool, for convention, address must be (LOCALHOST)
-    CreatePool(ProtocolServerConfig<ConfigP2P<BlockT>>),
+    // boxed, the config is far bigger than every other message
+    CreatePool(Box<ProtocolServerConfig<ConfigP2P<BlockT>>>),
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -61,4 +63,9 @@ pub enum ShareVerificationError {
     BadRewards,
     BadLinkMain,
     BadLinkP2P,
+    AlreadyKnown,
+    // the share was kept as an orphan, its parent should be requested
+    MissingParent { from_height: u32, count: u8 },
+    // not after the median time of its ancestors or too far in the future
+    BadTime,
 }
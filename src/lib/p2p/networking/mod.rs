@@ -13,4 +13,6 @@ pub mod messages;
 pub mod pplns;
 pub mod config;
 pub mod pool_manager;
-pub mod share;
\ No newline at end of file
+pub mod share;
+pub mod framing;
+pub mod sync;
\ No newline at end of file
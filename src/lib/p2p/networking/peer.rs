@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr};
 
+use crate::server::Notifier;
+
 type UnixMs = u64;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,6 +19,9 @@ pub struct Peer {
     #[serde(default = "bool::default")]
     #[serde(skip_serializing_if = "std::ops::Not::not")]
     pub connected: bool,
+
+    #[serde(skip)]
+    pub notifier: Option<Notifier>,
 }
 
 impl Peer {
@@ -27,6 +32,7 @@ impl Peer {
             authorized: None,
             listening_port: None,
             connected: true,
+            notifier: None,
         }
     }
 }
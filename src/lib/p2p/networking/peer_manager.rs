@@ -2,6 +2,7 @@ use std::{
     fs,
     net::{IpAddr, SocketAddr},
     path::Path,
+    sync::Mutex,
 };
 
 use itertools::Itertools;
@@ -10,6 +11,8 @@ use super::{peer::Peer, utils::time_now_ms};
 
 pub struct PeerManager {
     peers_dir: Box<Path>,
+    // connections from the same ip share a file, don't read it half written
+    file_lock: Mutex<()>,
 }
 
 impl PeerManager {
@@ -18,11 +21,15 @@ impl PeerManager {
         buf.push("peers");
         let peers_dir = buf.into_boxed_path();
 
-        Self { peers_dir }
+        Self {
+            peers_dir,
+            file_lock: Mutex::new(()),
+        }
     }
 
     pub fn load_peer(&self, addr: IpAddr) -> std::io::Result<Peer> {
         let path = self.get_peer_path(addr);
+        let _lock = self.file_lock.lock().unwrap();
         Ok(serde_json::from_slice(&fs::read(&path)?)
             .expect(&format!("Bad peer file at: {}", path.display())))
     }
@@ -31,6 +38,8 @@ impl PeerManager {
         let peer = match self.load_peer(address.ip()) {
             Ok(mut exists) => {
                 // we are about to connect... this method is called on connection.
+                // the file is per ip, the connection's own port wins
+                exists.address = address;
                 exists.connected = true;
                 exists
             }
@@ -47,6 +56,7 @@ impl PeerManager {
     pub fn save_peer(&self, peer: &Peer) {
         let path = self.get_peer_path(peer.address.ip());
 
+        let _lock = self.file_lock.lock().unwrap();
         fs::write(path, serde_json::to_string_pretty(peer).unwrap()).unwrap();
     }
 
@@ -10,7 +10,7 @@ use crate::{address::Address, coins::coin::Coin, p2p::consensus::block_manager::
 
 use super::{
     block::EncodeErrorP2P,
-    hard_config::{DEV_ADDRESS_BTC_STR, PPLNS_DIFF_MULTIPLIER, PPLNS_SHARE_UNITS},
+    hard_config::{MAX_REORG_DEPTH, PPLNS_DIFF_MULTIPLIER, PPLNS_SHARE_UNITS},
     share::ShareP2P,
 };
 
@@ -26,6 +26,7 @@ impl<A: Address> ScoreChanges<A> {
     pub fn new(
         current_scores: Vec<(A::FromScript, u64)>,
         mut last_scores: HashMap<A, u64>,
+        network: A::Network,
     ) -> Result<ScoreChanges<A>, EncodeErrorP2P> {
         let mut removed = Vec::new();
         let mut added = Vec::new();
@@ -33,7 +34,7 @@ impl<A: Address> ScoreChanges<A> {
         let exp_new_addrs = current_scores.len() as i64 - last_scores.len() as i64;
 
         for (key, score) in current_scores.into_iter() {
-            let addr = match A::from_script(&key) {
+            let addr = match A::from_script(&key, network) {
                 Ok(k) => k,
                 Err(_e) => return Err(EncodeErrorP2P::InvalidAddress),
             };
@@ -63,9 +64,9 @@ impl<A: Address> ScoreChanges<A> {
     }
 
     // genesis block will give the rest of the profits to the dev addr
-    pub fn genesis() -> Self {
+    pub fn genesis(network: A::Network) -> Self {
         Self {
-            added: Vec::from([(A::from_string(DEV_ADDRESS_BTC_STR).unwrap(), MAX_SCORE)]),
+            added: Vec::from([(A::dev_address(network), MAX_SCORE)]),
             removed: Vec::new(),
         }
     }
@@ -77,6 +78,8 @@ pub struct WindowPPLNS<C: Coin> {
     pub address_scores: HashMap<C::Address, Score>,
     pub oldest_height: u32,
     pplns_sum: Score,
+    // how to take back each of the last added shares, newest at the back
+    undo: VecDeque<WindowUndo<C>>,
 }
 
 #[derive(Clone)]
@@ -84,6 +87,16 @@ pub struct WindowEntry<C: Coin> {
     pub share: ShareP2P<C>,
     pub score: Score,
 }
+
+struct WindowUndo<C: Coin> {
+    // entries that fell out of the window, oldest first
+    evicted: Vec<WindowEntry<C>>,
+    // score cut from the window sum at the oldest remaining entry, and that entry's score before
+    trimmed: Score,
+    trimmed_score: Score,
+    // addresses that got their first score from the share
+    new_addresses: Vec<C::Address>,
+}
 // pub static PPLNS_DIFF_MULTIPLIER_DECIMAL: Decimal =PPLNS_DIFF_MULTIPLIER.into();
 
 pub const MAX_SCORE: u64 = PPLNS_DIFF_MULTIPLIER * PPLNS_SHARE_UNITS;
@@ -112,6 +125,7 @@ impl<C: Coin> WindowPPLNS<C> {
             pplns_sum: 0,
             oldest_height: 0,
             address_scores: HashMap::new(),
+            undo: VecDeque::new(),
         };
 
         me.add_entry(genesis_entry);
@@ -131,31 +145,87 @@ impl<C: Coin> WindowPPLNS<C> {
         };
 
         self.remove_scores(&entry.share.score_changes.removed);
+        let new_addresses = entry
+            .share
+            .score_changes
+            .added
+            .iter()
+            .filter(|(addr, _)| !self.address_scores.contains_key(addr))
+            .map(|(addr, _)| addr.clone())
+            .collect();
         self.add_entry(entry);
 
         // clean expired pplns...
         // pplns window must always be full.
+        let mut evicted = Vec::new();
+        let trimmed;
+        let trimmed_score;
         loop {
             let entry = self.pplns_window.pop_back().unwrap();
 
             if self.pplns_sum - entry.score > MAX_SCORE {
                 self.pplns_sum -= entry.score;
+                evicted.push(entry);
             } else {
+                // only the part that's over the window expires
                 let remaining = self.pplns_sum - MAX_SCORE;
                 self.pplns_window.push_back(WindowEntry {
                     share: entry.share,
-                    score: remaining,
+                    score: entry.score - remaining,
                 });
                 self.pplns_sum -= remaining;
+                trimmed = remaining;
+                trimmed_score = entry.score;
 
                 break;
             }
         }
 
+        self.undo.push_back(WindowUndo {
+            evicted,
+            trimmed,
+            trimmed_score,
+            new_addresses,
+        });
+        if self.undo.len() > MAX_REORG_DEPTH as usize {
+            self.undo.pop_front();
+        }
+
         // self.oldest_height = last_removed.share.encoded.height;
         debug_assert_eq!(self.pplns_sum, MAX_SCORE);
     }
 
+    // how many of the last added shares can be taken back
+    pub fn rewind_depth(&self) -> usize {
+        self.undo.len()
+    }
+
+    // takes back the last added share, false if it's too old to be taken back
+    pub fn rewind(&mut self) -> bool {
+        let undo = match self.undo.pop_back() {
+            Some(k) => k,
+            None => return false,
+        };
+
+        let entry = self.pplns_window.pop_front().unwrap();
+        self.pplns_sum -= entry.score;
+        self.remove_scores(&entry.share.score_changes.added);
+        self.add_scores(&entry.share.score_changes.removed);
+        for addr in &undo.new_addresses {
+            self.address_scores.remove(addr);
+        }
+
+        self.pplns_window.back_mut().unwrap().score = undo.trimmed_score;
+        self.pplns_sum += undo.trimmed;
+        for entry in undo.evicted.into_iter().rev() {
+            self.pplns_sum += entry.score;
+            self.pplns_window.push_back(entry);
+        }
+
+        debug_assert_eq!(self.pplns_sum, MAX_SCORE);
+        true
+    }
+
     pub fn verify_changes(&self, changes: &ScoreChanges<C::Address>, score: Score) -> bool {
         let added: Score = changes.added.iter().map(|x| x.1).sum();
         let removed: Score = changes.removed.iter().map(|x| x.1).sum();
@@ -304,3 +374,55 @@ impl<C: Coin> WindowPPLNS<C> {
 //         assert_eq!(res.err().unwrap(), ShareVerificationError::BadEncoding);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{Network, ScriptBuf};
+    use crypto_bigint::U256;
+
+    use crate::{
+        coins::bitcoin::{Btc, MyBtcAddr},
+        p2p::{
+            consensus::block_manager::ProcessedShare,
+            networking::share::{CoinbaseEncodedP2P, ShareP2P},
+        },
+    };
+
+    use super::{ScoreChanges, WindowPPLNS, MAX_SCORE};
+
+    fn share(addr: &MyBtcAddr, score: u64) -> ShareP2P<Btc> {
+        ShareP2P {
+            block: bitcoin::blockdata::constants::genesis_block(Network::Regtest),
+            encoded: CoinbaseEncodedP2P::default(),
+            score_changes: ScoreChanges {
+                added: vec![(addr.clone(), score)],
+                removed: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn trimmed_entry_keeps_unexpired_score() {
+        let dev = MyBtcAddr(bitcoin::Address::p2wsh(&ScriptBuf::new(), Network::Regtest));
+        let miner = MyBtcAddr(bitcoin::Address::p2wsh(
+            &ScriptBuf::new_op_return(&[1]),
+            Network::Regtest,
+        ));
+        let mut window = WindowPPLNS::<Btc>::new(share(&dev, MAX_SCORE));
+
+        let score = MAX_SCORE / 4;
+        window.add(ProcessedShare {
+            inner: share(&miner, score),
+            hash: U256::ZERO,
+            score,
+        });
+
+        let scores: Vec<u64> = window.pplns_window.iter().map(|e| e.score).collect();
+        assert_eq!(scores, vec![score, MAX_SCORE - score]);
+        assert_eq!(scores.iter().sum::<u64>(), MAX_SCORE);
+
+        assert!(window.rewind());
+        let scores: Vec<u64> = window.pplns_window.iter().map(|e| e.score).collect();
+        assert_eq!(scores, vec![MAX_SCORE]);
+    }
+}
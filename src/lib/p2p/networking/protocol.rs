@@ -2,34 +2,40 @@ use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crypto_bigint::U256;
-use io_arc::IoArc;
+use flume::{Receiver, Sender, TrySendError};
 use log::{info, warn};
-use mio::{net::TcpStream, Token};
 use sha2::digest::typenum::U2;
 
 use std::net::SocketAddr;
 
 use crate::{
     address::Address,
+    framing::Framing,
+    p2p::duplicate_checker::DuplicateHashChecker,
     protocol::Protocol,
-    server::{respond, Notifier},
-    stratum::{client::StratumClient, job_fetcher::BlockFetcher}, p2p::consensus::{consensus::ConsensusConfigP2P, block_manager::BlockManager, target_manager::TargetManager},
+    server::Notifier,
+    stratum::{client::StratumClient, header::BlockHeader, job_fetcher::{BlockFetcher, DaemonAuth}}, p2p::consensus::{consensus::{ConsensusConfigP2P, RetargetAlgorithm}, block_manager::{BlockManager, ProcessedShare, ShareOrigin, TipUpdate}, target_manager::TargetManager},
 };
 
 use super::{
     block::Block,
     config::{ConfigP2P},
     difficulty,
-    hard_config::{CURRENT_VERSION, DEV_ADDRESS_BTC_STR, OLDEST_COMPATIBLE_VERSION},
+    framing::FramingP2P,
+    hard_config::{
+        CURRENT_VERSION, MAX_REORG_DEPTH, OLDEST_COMPATIBLE_VERSION, SEEN_SHARES_CAPACITY,
+        SYNC_PAGE_SIZE,
+    },
     messages::*,
     peer::Peer,
     peer_manager::PeerManager,
     pplns::{self, ScoreChanges, WindowPPLNS},
     share::{CoinbaseEncodedP2P, ShareP2P},
+    sync::SyncState,
     utils::time_now_ms,
 };
 use crate::coins::coin::Coin;
@@ -39,12 +45,17 @@ pub struct ProtocolP2P<C: Coin> {
     pub pplns_window: Mutex<WindowPPLNS<C>>,
     pub conf: ConfigP2P<C::BlockT>,
     hello_message: Messages<C::BlockT>,
-    pub peers: Mutex<HashMap<Token, Notifier>>,
+    // authorized peers, shares are relayed to them
+    pub peers: Mutex<HashMap<SocketAddr, Notifier>>,
+    seen_shares: Mutex<DuplicateHashChecker>,
     // data_dir: Box<Path>,
     pub peer_manager: PeerManager,
     pub block_manager: BlockManager<C>,
-    pub target_manager: Mutex<TargetManager>,
+    pub target_manager: TargetManager,
     pub daemon_cli: C::Fetcher,
+    sync_state: Mutex<SyncState>,
+    // woken when the best share moves, as it changes the coinbase of every job
+    tip_listeners: Mutex<Vec<Sender<()>>>,
 }
 
 pub type Reward = u64;
@@ -55,31 +66,44 @@ impl<C: Coin> Protocol for ProtocolP2P<C> {
     type Config = ConfigP2P<C::BlockT>; // data dir, listening port
     type ClientContext = Peer;
     type ProcessingContext = ();
+    type Framing = FramingP2P;
 
     fn new(conf: Self::Config) -> Self {
-        let daemon_cli = C::Fetcher::new(conf.rpc_url.as_ref()).unwrap();
+        let daemon_cli = C::Fetcher::new(conf.rpc_url.as_ref(), &conf.rpc_auth)
+            .expect("Failed to connect to the daemon");
 
         // only share that's not actually encoded in the blockchain (as it would require much resources)
         let genesis_share = ShareP2P {
             block: conf.consensus.genesis_block.clone(),
             encoded: CoinbaseEncodedP2P::default(),
-            score_changes: ScoreChanges::genesis(),
+            score_changes: ScoreChanges::genesis(conf.consensus.network),
         };
         // BlockManager::decode_share(conf.consensus.genesis_block.clone(), &HashMap::new())
         //     .unwrap();
 
+        let target_manager = TargetManager::new::<C>(
+            &conf.consensus,
+            Duration::from_millis(conf.consensus.block_time_ms as u64),
+            conf.consensus.diff_adjust_blocks,
+        );
+
         Self {
             pplns_window: Mutex::new(WindowPPLNS::new(genesis_share.clone())),
             hello_message: Messages::Hello(Hello::new(conf.listening_port, &conf.consensus)),
-            target_manager: Mutex::new(TargetManager::new::<C>(
-                &conf.consensus,
-                Duration::from_millis(conf.consensus.block_time_ms as u64),
-                conf.consensus.diff_adjust_blocks,
-            )),
-            block_manager: BlockManager::new(genesis_share, conf.data_dir.clone()),
+            block_manager: BlockManager::new(
+                genesis_share,
+                target_manager.genesis_adjustment(),
+                conf.data_dir.clone(),
+                conf.consensus.network,
+            ),
+            target_manager,
             peers: Mutex::new(HashMap::new()),
+            seen_shares: Mutex::new(DuplicateHashChecker::new(SEEN_SHARES_CAPACITY)),
             peer_manager: PeerManager::new(conf.data_dir.clone()),
             daemon_cli,
+            // without a server there is no one to sync from
+            sync_state: Mutex::new(SyncState::Synced),
+            tip_listeners: Mutex::new(Vec::new()),
             conf,
         }
     }
@@ -109,7 +133,7 @@ impl<C: Coin> Protocol for ProtocolP2P<C> {
     fn create_client(
         &self,
         address: SocketAddr,
-        _notifier: Notifier,
+        notifier: Notifier,
     ) -> Option<Self::ClientContext> {
         let peer_lock = self.peers.lock().unwrap();
         let connection_count = peer_lock.len() as u32;
@@ -117,45 +141,63 @@ impl<C: Coin> Protocol for ProtocolP2P<C> {
         if connection_count >= self.conf.max_peer_connections {
             None
         } else {
-            // peer_lock.insert(token, notifier);
-
-            Some(self.peer_manager.load_connecting_peer(address))
+            let mut peer = self.peer_manager.load_connecting_peer(address);
+            peer.notifier = Some(notifier);
+            Some(peer)
         }
     }
 
-    fn client_conncted(&self, stream: IoArc<TcpStream>, ctx: Arc<Mutex<Self::ClientContext>>) {
+    fn client_conncted(&self, notifier: Notifier, ctx: Arc<Mutex<Self::ClientContext>>) {
         info!("Sent hello to: {}", ctx.lock().unwrap().address);
-        Self::send_message(&self.hello_message, stream.as_ref());
+        Self::send_message(&self.hello_message, &notifier);
     }
 
-    // TODO clean
     fn delete_client(&self, ctx: Arc<Mutex<Self::ClientContext>>) {
-        // TODO: CHECK
-        // self.peers.lock().unwrap().remove(&token);
-
         let mut lock = ctx.lock().unwrap();
+        self.peers.lock().unwrap().remove(&lock.address);
+
         lock.last_connection_fail = Some(time_now_ms());
         lock.connected = false;
         self.peer_manager.save_peer(&*lock);
+
+        let mut sync = self.sync_state.lock().unwrap();
+        match *sync {
+            SyncState::Syncing { peer, .. } if peer == lock.address => {
+                warn!("Sync peer {} disconnected, continuing from local tip", peer);
+                *sync = SyncState::Synced;
+            }
+            SyncState::Pending { .. } => {
+                warn!("Failed to sync from {}, continuing from local tip", lock.address);
+                *sync = SyncState::Synced;
+            }
+            _ => {}
+        }
     }
 
     fn create_ptx(&self) -> Self::ProcessingContext {}
 }
 
 impl<C: Coin> ProtocolP2P<C> {
+    // pick up where the last run stopped, the peers only need to send what we missed
+    pub fn restore(&self) -> u32 {
+        self.block_manager
+            .restore(&self.target_manager, &mut self.pplns_window.lock().unwrap())
+    }
+
     pub fn get_new_pool_config(
         data_dir: Box<Path>,
         pool_name: String,
         rpc_url: String,
+        rpc_auth: DaemonAuth,
+        network: <C::BlockT as Block>::Network,
         diff1: u64,
         block_time_ms: u64,
     ) -> ConfigP2P<C::BlockT> {
-        let daemon_cli = C::Fetcher::new(rpc_url.as_ref()).unwrap();
+        let daemon_cli = C::Fetcher::new(rpc_url.as_ref(), &rpc_auth)
+            .expect("Failed to connect to the daemon");
 
         let rewards: [(<<C as Coin>::BlockT as Block>::Script, u64); 1] = [(
-            C::Address::from_string(DEV_ADDRESS_BTC_STR)
-                .unwrap()
-                .to_script(),
+            C::Address::dev_address(network).to_script(),
             pplns::MAX_SCORE,
         )];
 
@@ -170,6 +212,7 @@ impl<C: Coin> ProtocolP2P<C> {
             max_peer_connections: 32,
             consensus: ConsensusConfigP2P {
                 name: pool_name,
+                network,
                 parent_pool_hash: U256::ZERO,
                 block_time_ms,
                 diff_adjust_blocks: 16,
@@ -178,8 +221,10 @@ impl<C: Coin> ProtocolP2P<C> {
                 target_1: difficulty::get_target_from_diff_units(diff1, &C::DIFF1),
                 default_port_p2p: 0,
                 default_port_stratum: 0,
+                retarget: RetargetAlgorithm::default(),
             },
             rpc_url,
+            rpc_auth,
             data_dir,
             listening_port: 0,
         }
@@ -202,12 +247,14 @@ impl<C: Coin> ProtocolP2P<C> {
             Messages::GetShares { from_height, count } => {
                 self.handle_get_shares(from_height, count)
             }
-            Messages::Shares(_shares) => todo!(),
+            Messages::Shares(shares) => self.handle_shares(ctx, shares),
             Messages::ShareSubmit(share) => {
-                self.handle_share_submit(SubmittingContext::P2P(ctx.lock().unwrap().address), share)
+                let address = ctx.lock().unwrap().address;
+                self.handle_share_submit(SubmittingContext::P2P(address), share)
             }
             Messages::Reject => {
                 warn!("Peer rejected");
+                self.stop_syncing_from(ctx.lock().unwrap().address);
                 None
             }
             Messages::CreatePool(_) => todo!(),
@@ -218,7 +265,7 @@ impl<C: Coin> ProtocolP2P<C> {
             Messages::RoundInfo {
                 start_height,
                 current_height,
-            } => todo!(),
+            } => self.handle_round_info(ctx, start_height, current_height),
         }
     }
 
@@ -228,6 +275,7 @@ impl<C: Coin> ProtocolP2P<C> {
             lock.authorized = Some(hello.version);
             lock.listening_port = Some(hello.listening_port);
             self.peer_manager.save_peer(&*lock);
+            self.add_authorized_peer(&lock);
 
             Some(Messages::VerAck)
         } else {
@@ -240,7 +288,170 @@ impl<C: Coin> ProtocolP2P<C> {
         let mut lock = ctx.lock().unwrap();
         lock.authorized = Some(CURRENT_VERSION);
         self.peer_manager.save_peer(&*lock);
-        None
+        self.add_authorized_peer(&lock);
+
+        // find out whether the peer is ahead of us
+        Some(Messages::GetRoundInfo)
+    }
+
+    fn add_authorized_peer(&self, peer: &Peer) {
+        if let Some(notifier) = &peer.notifier {
+            self.peers
+                .lock()
+                .unwrap()
+                .insert(peer.address, notifier.clone());
+        }
+    }
+
+    fn handle_round_info(
+        &self,
+        ctx: Arc<Mutex<Peer>>,
+        start_height: u32,
+        current_height: u32,
+    ) -> Option<Messages<C::BlockT>> {
+        let address = ctx.lock().unwrap().address;
+        let local_height = self.block_manager.p2p_tip().inner.encoded.height;
+        let mut sync = self.sync_state.lock().unwrap();
+
+        if current_height <= local_height {
+            if matches!(*sync, SyncState::Pending { .. }) {
+                info!("Synced, local height: {}", local_height);
+                *sync = SyncState::Synced;
+            }
+            return None;
+        }
+
+        if let SyncState::Syncing { peer, .. } = *sync {
+            info!("Already syncing from {}, ignoring round info from {}", peer, address);
+            return None;
+        }
+
+        info!(
+            "Syncing from {}, local height: {}, peer height: {}, peer round start: {}",
+            address, local_height, current_height, start_height
+        );
+        // the peer's round may fork off ours anywhere we can still reorg, deeper shares are pruned
+        let from_height = start_height
+            .min(local_height + 1)
+            .max(local_height.saturating_sub(MAX_REORG_DEPTH) + 1)
+            .max(1);
+        *sync = SyncState::Syncing {
+            peer: address,
+            target_height: current_height,
+            next_height: from_height + SYNC_PAGE_SIZE as u32,
+            last_reply: Instant::now(),
+        };
+
+        Some(Messages::GetShares {
+            from_height,
+            count: SYNC_PAGE_SIZE,
+        })
+    }
+
+    fn handle_shares(
+        &self,
+        ctx: Arc<Mutex<Peer>>,
+        shares: Vec<C::BlockT>,
+    ) -> Option<Messages<C::BlockT>> {
+        let address = ctx.lock().unwrap().address;
+
+        // any page from the sync peer is an answer, even one that only fills a fork
+        if let SyncState::Syncing {
+            peer,
+            ref mut last_reply,
+            ..
+        } = *self.sync_state.lock().unwrap()
+        {
+            if peer == address {
+                *last_reply = Instant::now();
+            }
+        }
+
+        let mut missing = None;
+        let received = shares.len();
+        for share in shares {
+            match self.accept_share(share, ShareOrigin::Historic) {
+                Ok(_) | Err(ShareVerificationError::AlreadyKnown) => {}
+                Err(ShareVerificationError::MissingParent { from_height, count }) => {
+                    missing.get_or_insert(Messages::GetShares { from_height, count });
+                }
+                Err(e) => {
+                    warn!("Rejected synced share from {} for {:?}", address, e);
+                    self.stop_syncing_from(address);
+                    return None;
+                }
+            }
+        }
+
+        // the page forks off our chain, fetch the branch first
+        if missing.is_some() {
+            info!("Requesting missing ancestors from {}", address);
+            return missing;
+        }
+
+        let local_height = self.block_manager.p2p_tip().inner.encoded.height;
+        let mut sync = self.sync_state.lock().unwrap();
+        match *sync {
+            SyncState::Syncing {
+                peer,
+                target_height,
+                ref mut next_height,
+                ..
+            } if peer == address => {
+                // our best chain can be lower than the peer's but heavier, so page by height, not by our tip
+                if *next_height > target_height || received == 0 {
+                    info!("Synced from {}, local height: {}", peer, local_height);
+                    *sync = SyncState::Synced;
+                    None
+                } else {
+                    info!("Syncing... {}/{}", local_height, target_height);
+                    let from_height = *next_height;
+                    *next_height += SYNC_PAGE_SIZE as u32;
+                    Some(Messages::GetShares {
+                        from_height,
+                        count: SYNC_PAGE_SIZE,
+                    })
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn stop_syncing_from(&self, address: SocketAddr) {
+        let mut sync = self.sync_state.lock().unwrap();
+        match *sync {
+            SyncState::Syncing { peer, .. } if peer == address => {
+                warn!("Stopped syncing from {}, continuing from local tip", peer);
+                *sync = SyncState::Synced;
+            }
+            // the peer we asked for its round info won't give it
+            SyncState::Pending { .. } => {
+                warn!("Failed to sync from {}, continuing from local tip", address);
+                *sync = SyncState::Synced;
+            }
+            _ => {}
+        }
+    }
+
+    // called before connecting to peers, stratum jobs are held back until one of them tells us we're up to date
+    pub fn begin_sync(&self) {
+        *self.sync_state.lock().unwrap() = SyncState::Pending {
+            since: Instant::now(),
+        };
+    }
+
+    pub fn stop_sync(&self) {
+        info!("No peers to sync from, continuing from local tip");
+        *self.sync_state.lock().unwrap() = SyncState::Synced;
+    }
+
+    pub fn is_synced(&self) -> bool {
+        let mut sync = self.sync_state.lock().unwrap();
+        if sync.timed_out(Instant::now()) {
+            warn!("Sync timed out, continuing from local tip");
+            *sync = SyncState::Synced;
+        }
+        *sync == SyncState::Synced
     }
 
     fn handle_get_shares(&self, from_height: u32, count: u8) -> Option<Messages<C::BlockT>> {
@@ -258,23 +469,43 @@ impl<C: Coin> ProtocolP2P<C> {
         ctx: SubmittingContext,
         share: C::BlockT,
     ) -> Option<Messages<C::BlockT>> {
-        let targetman = self.target_manager.lock().unwrap();
+        // shares are relayed by every node, so we'll receive most of them more than once
+        let hash = share.get_header().get_hash();
+        if self.seen_shares.lock().unwrap().contains(&hash) {
+            return None;
+        }
 
-        let mut pplns_lock = self.pplns_window.lock().unwrap();
-        match self
-            .block_manager
-            .process_share(share, &targetman, &pplns_lock)
-        {
-            Ok(pshare) => {
+        let res = self.accept_share(share, ShareOrigin::Live);
+        // a share that's missing something we haven't seen yet may still be valid when relayed again
+        if !matches!(
+            res,
+            Err(ShareVerificationError::MissingParent { .. } | ShareVerificationError::BadLinkMain)
+        ) {
+            self.seen_shares.lock().unwrap().insert(&hash);
+        }
+
+        match res {
+            Ok((pshare, update)) => {
                 // check if valid mainnet block
                 // let main_target = pshare.inner.block.get_header().get_target();
 
                 info!(
-                    "Accepted new share submission from peer: {:?}, hash: {}",
-                    ctx, &pshare.hash
+                    "Accepted new share submission from peer: {:?}, hash: {}, {:?}",
+                    ctx, &pshare.hash, update
                 );
 
-                pplns_lock.add(pshare);
+                // side chain shares are relayed too, so every node sees the same tree
+                let sender = match ctx {
+                    SubmittingContext::Stratum(_) => None,
+                    SubmittingContext::P2P(address) => Some(address),
+                };
+                self.relay_share(&pshare.inner.block, sender);
+            }
+            Err(ShareVerificationError::MissingParent { from_height, count }) => {
+                if let SubmittingContext::P2P(address) = ctx {
+                    info!("Requesting missing parent of {} from {}", hash, address);
+                    return Some(Messages::GetShares { from_height, count });
+                }
             }
             Err(e) => {
                 info!("Rejected share from {:?} for {:?}", ctx, e)
@@ -284,16 +515,97 @@ impl<C: Coin> ProtocolP2P<C> {
         None
     }
 
-    pub fn send_message(message: &Messages<C::BlockT>, stream: &TcpStream) {
-        respond(stream, Self::serialize_message(message).as_ref())
+    // verifies the share, the pplns window follows the best chain
+    fn accept_share(
+        &self,
+        share: C::BlockT,
+        origin: ShareOrigin,
+    ) -> Result<(ProcessedShare<C>, TipUpdate), ShareVerificationError> {
+        let res = self.process_share(share, origin)?;
+        self.connect_orphans(res.0.hash);
+        Ok(res)
     }
 
-    pub fn serialize_message(message: &Messages<C::BlockT>) -> Vec<u8> {
-        let mut bytes = bincode::serialize(message).unwrap();
-        bytes.push('\n' as u8);
-        bytes
+    fn process_share(
+        &self,
+        share: C::BlockT,
+        origin: ShareOrigin,
+    ) -> Result<(ProcessedShare<C>, TipUpdate), ShareVerificationError> {
+        let mut pplns_lock = self.pplns_window.lock().unwrap();
+        let res = self
+            .block_manager
+            .process_share(share, &self.target_manager, &mut pplns_lock, origin)?;
+        std::mem::drop(pplns_lock);
+
+        if !matches!(res.1, TipUpdate::SideChain) {
+            self.notify_tip_changed();
+        }
+        Ok(res)
+    }
+
+    // a pending wake up covers any number of tip changes
+    pub fn subscribe_tip_changes(&self) -> Receiver<()> {
+        let (tx, rx) = flume::bounded(1);
+        self.add_tip_listener(tx);
+        rx
+    }
 
-        // can be done more elgantly with a custom buffer reader... or can it?
+    // for listeners that are also woken by others
+    pub fn add_tip_listener(&self, tx: Sender<()>) {
+        self.tip_listeners.lock().unwrap().push(tx);
+    }
+
+    fn notify_tip_changed(&self) {
+        self.tip_listeners
+            .lock()
+            .unwrap()
+            .retain(|tx| !matches!(tx.try_send(()), Err(TrySendError::Disconnected(_))));
+    }
+
+    // processes the orphans that were waiting for the given share, and their own orphans
+    fn connect_orphans(&self, parent: U256) {
+        let mut worklist = vec![parent];
+
+        while let Some(parent) = worklist.pop() {
+            for orphan in self.block_manager.take_orphans(&parent) {
+                match self.process_share(orphan.block, orphan.origin) {
+                    Ok((pshare, update)) => {
+                        info!("Connected orphan share: {}, {:?}", pshare.hash, update);
+
+                        // it wasn't relayed when it arrived
+                        if orphan.origin == ShareOrigin::Live {
+                            self.relay_share(&pshare.inner.block, None);
+                        }
+                        worklist.push(pshare.hash);
+                    }
+                    Err(e) => info!("Rejected orphan share {} for {:?}", orphan.hash, e),
+                }
+            }
+        }
+    }
+
+    // broadcasts the share to every authorized peer except the one we got it from
+    fn relay_share(&self, share: &C::BlockT, sender: Option<SocketAddr>) {
+        let bytes = Self::serialize_message(&Messages::ShareSubmit(share.clone()));
+        let peers = self.peers.lock().unwrap();
+
+        let mut relayed = 0;
+        for (address, notifier) in peers.iter() {
+            if Some(*address) == sender {
+                continue;
+            }
+            notifier.notify(&bytes);
+            relayed += 1;
+        }
+        info!("Relayed share to {} peers", relayed);
+    }
+
+    pub fn send_message(message: &Messages<C::BlockT>, notifier: &Notifier) {
+        notifier.notify(Self::serialize_message(message).as_ref())
+    }
+
+    pub fn serialize_message(message: &Messages<C::BlockT>) -> Vec<u8> {
+        FramingP2P::encode(&bincode::serialize(message).unwrap())
     }
 
     // tells the server who to connect to at bootstrap
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use super::config::ConfigP2P;
@@ -22,26 +22,50 @@ impl<C: Coin + 'static> ServerP2P<C> {
 
         let mut se = Self { server, protocol };
 
-        se.connect();
+        // only a node restores its shares, a pool being created starts from its genesis
+        se.protocol.restore();
+        se.protocol.begin_sync();
+        if se.connect() == 0 {
+            // first node of the pool, nothing to sync from
+            se.protocol.stop_sync();
+        }
         se
     }
 
-    pub fn connect(&mut self) {
+    // returns the amount of new connections
+    pub fn connect(&mut self) -> usize {
         let missing =
             self.protocol.conf.max_peer_connections - self.server.get_connection_count() as u32;
 
+        let mut connected = 0;
         // info!("Missing connections...");
         for i in self.protocol.peers_to_connect(missing) {
             if i == self.server.conf.address {
                 continue;
             }
 
-            let _token = self.server.connect(i);
+            if self.server.connect(i).is_some() {
+                connected += 1;
+            }
         }
+        connected
     }
 
-    pub fn process_p2p(&mut self) {
+    pub fn connect_to(&mut self, address: SocketAddr) -> bool {
+        self.server.connect(address).is_some()
+    }
+
+    pub fn disconnect_from(&mut self, address: SocketAddr) -> bool {
+        self.server.disconnect_address(address)
+    }
+
+    // serves the current connections without dialing new peers
+    pub fn process_requests(&mut self) {
         self.server.process_requests();
+    }
+
+    pub fn process_p2p(&mut self) {
+        self.process_requests();
 
         // }
         // TODO timer...
@@ -18,43 +18,22 @@ impl<C: Coin> StratumHandler<C> for ProtocolP2P<C> {
         block: &C::BlockT,
         hash: U256,
     ) {
-        let lock = self.target_manager.lock().unwrap();
-        let target = lock.target();
+        let target = self.block_manager.p2p_target();
         log::info!("Hash {:x}", hash);
         log::info!("Target {:x}", target);
 
-        if &hash > target {
+        if hash > target {
             return;
         }
-        std::mem::drop(lock);
 
         info!("LOCAL FOUND new share submission hash: {}", &hash);
 
-        self.handle_share_submit(
-            SubmittingContext::Stratum(ctx.lock().unwrap().address),
-            block.clone(),
-        );
+        let address = ctx.lock().unwrap().address;
+        self.handle_share_submit(SubmittingContext::Stratum(address), block.clone());
     }
 
-    fn on_new_block(&self, height: u32, block_hash: &U256) {
-        self.block_manager.new_block(height, block_hash);
-        // let mut target_lock = self.target_manager.lock().unwrap();
-        // target_lock.adjust(height, block);
-
-        let _peer_lock = self.peers.lock().unwrap();
-
-        // info!("Current p2p target: {}", target_lock.target());
-        // for (addr, (share, diff)) in &*lock {
-        //     let share = ShareP2P {
-        //         address: addr.clone(),
-        //         block: share.clone(),
-        //     };
-        //     let message = Messages::ShareSubmit(share);
-        //     for (token, stream) in &*peer_lock {
-        //         Self::send_message(&message, stream.clone());
-        //         info!("Submitted share: {:?} for address {}", message, addr);
-        //     }
-        // }
+    fn on_new_block(&self, height: u32, block_hash: &U256, target: &U256) {
+        self.block_manager.new_block(height, block_hash, target);
     }
 }
 
@@ -74,7 +53,7 @@ impl<C: Coin> StratumHandler<C> for CompleteStratumHandler<C> {
         self.p2p.on_valid_share(ctx, address, share, hash)
     }
 
-    fn on_new_block(&self, height: u32, block_hash: &U256) {
-        self.p2p.on_new_block(height, block_hash)
+    fn on_new_block(&self, height: u32, block_hash: &U256, target: &U256) {
+        self.p2p.on_new_block(height, block_hash, target)
     }
 }
@@ -0,0 +1,58 @@
+use std::{net::SocketAddr, time::Instant};
+
+use super::hard_config::SYNC_REPLY_TIMEOUT;
+
+// a node only serves stratum jobs once it's synced, otherwise its miners would work on a stale share chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncState {
+    // connecting to peers, waiting for one of them to tell us its round info
+    Pending { since: Instant },
+    // paging through GetShares from the given peer until we reach its height
+    Syncing {
+        peer: SocketAddr,
+        target_height: u32,
+        // the first height of the next page, only ever goes up so syncing ends
+        next_height: u32,
+        last_reply: Instant,
+    },
+    Synced,
+}
+
+impl SyncState {
+    // a peer that never answers mustn't hold back stratum forever
+    pub fn timed_out(&self, now: Instant) -> bool {
+        let waiting_since = match self {
+            SyncState::Pending { since } => since,
+            SyncState::Syncing { last_reply, .. } => last_reply,
+            SyncState::Synced => return false,
+        };
+        now.saturating_duration_since(*waiting_since) > SYNC_REPLY_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SyncState;
+    use crate::p2p::networking::hard_config::SYNC_REPLY_TIMEOUT;
+
+    #[test]
+    fn silent_peers_time_out() {
+        let start = Instant::now();
+        let later = start + SYNC_REPLY_TIMEOUT + Duration::from_secs(1);
+
+        let pending = SyncState::Pending { since: start };
+        assert!(!pending.timed_out(start));
+        assert!(pending.timed_out(later));
+
+        let syncing = SyncState::Syncing {
+            peer: "127.0.0.1:1".parse().unwrap(),
+            target_height: 10,
+            next_height: 1,
+            last_reply: start,
+        };
+        assert!(syncing.timed_out(later));
+        assert!(!SyncState::Synced.timed_out(later));
+    }
+}
@@ -0,0 +1,482 @@
+// several p2p nodes on localhost in one process, each with its own data dir and mock daemon
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{hashes::Hash, Network, ScriptBuf};
+use crypto_bigint::{Encoding, U256};
+use flume::{Sender, TryRecvError};
+
+use crate::{
+    address::Address,
+    coins::bitcoin::{Btc, MyBtcAddr},
+    config::{ProtocolServerConfig, ServerConfig},
+    p2p::{
+        consensus::{
+            block_manager::{ShareOrigin, TipUpdate},
+            consensus::ConsensusConfigP2P,
+        },
+        networking::{
+            block::Block,
+            config::ConfigP2P,
+            difficulty::MAX_TARGET,
+            messages::ShareVerificationError,
+            pplns::{Score, MAX_SCORE},
+            protocol::{ProtocolP2P, SubmittingContext},
+            server::ServerP2P,
+            share::CoinbaseEncodedP2P,
+        },
+    },
+    stratum::{
+        header::BlockHeader,
+        job_fetcher::DaemonAuth,
+        mock_daemon::{MockChain, MockDaemon},
+    },
+};
+
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(60);
+// moved from the dev address to the miner by every scripted share
+const MINER_SHARE_SCORE: Score = MAX_SCORE / 100;
+
+enum Command {
+    Connect(SocketAddr),
+    Disconnect(SocketAddr),
+}
+
+struct SimNode {
+    protocol: Arc<ProtocolP2P<Btc>>,
+    address: SocketAddr,
+    daemon: MockDaemon,
+    commands: Sender<Command>,
+    thread: JoinHandle<()>,
+}
+
+impl SimNode {
+    fn start(consensus: ConsensusConfigP2P<bitcoin::Block>, daemon: MockDaemon, data_dir: PathBuf) -> Self {
+        fs::create_dir_all(data_dir.join("peers")).unwrap();
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server = ServerP2P::<Btc>::new(ProtocolServerConfig {
+            server_config: ServerConfig {
+                address,
+                processing_threads: 1,
+            },
+            protocol_config: ConfigP2P {
+                consensus,
+                max_peer_connections: 8,
+                rpc_url: daemon.url(),
+                rpc_auth: DaemonAuth::None,
+                data_dir: data_dir.into_boxed_path(),
+                listening_port: address.port(),
+            },
+        });
+        let protocol = server.protocol.clone();
+
+        update_main_tip(&protocol, &daemon);
+
+        // peers are only dialed when the simulation says so
+        let (commands, rx) = flume::unbounded();
+        let thread = std::thread::spawn(move || loop {
+            loop {
+                match rx.try_recv() {
+                    Ok(Command::Connect(address)) => {
+                        server.connect_to(address);
+                    }
+                    Ok(Command::Disconnect(address)) => {
+                        server.disconnect_from(address);
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            server.process_requests();
+        });
+
+        Self {
+            protocol,
+            address,
+            daemon,
+            commands,
+            thread,
+        }
+    }
+
+    // a block from another miner on the node's daemon only
+    fn advance_main(&self) {
+        self.daemon.chain.lock().unwrap().advance_tip();
+        update_main_tip(&self.protocol, &self.daemon);
+    }
+
+    // a share on top of the node's tip, paying the miner like the rest of the window
+    fn share_template(&self, miner: &MyBtcAddr) -> bitcoin::Block {
+        let mut scores = self.protocol.pplns_window.lock().unwrap().address_scores.clone();
+        *scores
+            .get_mut(&MyBtcAddr::dev_address(Network::Regtest))
+            .unwrap() -= MINER_SHARE_SCORE;
+        *scores.entry(miner.clone()).or_default() += MINER_SHARE_SCORE;
+
+        let mut vout: Vec<(ScriptBuf, Score)> = scores
+            .iter()
+            .map(|(addr, score)| (addr.to_script(), *score))
+            .collect();
+        vout.sort();
+
+        let encoded = {
+            let tip = self.protocol.block_manager.p2p_tip();
+            CoinbaseEncodedP2P {
+                prev_hash: tip.hash,
+                height: tip.inner.encoded.height + 1,
+                round_num: self.protocol.block_manager.round_num(),
+            }
+        };
+
+        let template = self.daemon.chain.lock().unwrap().parsed_template();
+        let mut share = bitcoin::Block::from_block_template(&template, vout.into_iter(), encoded).0;
+        // shares on the same main tip would share its time, but each must be after the last
+        share.header.time = self.protocol.block_manager.p2p_tip().inner.block.header.time + 1;
+        share
+    }
+}
+
+// stratum would normally tell the node which main block the live shares build on
+fn update_main_tip(protocol: &ProtocolP2P<Btc>, daemon: &MockDaemon) {
+    let chain = daemon.chain.lock().unwrap();
+    let template = chain.parsed_template();
+    let (next, _) = bitcoin::Block::from_block_template(
+        &template,
+        std::iter::empty(),
+        CoinbaseEncodedP2P::default(),
+    );
+    protocol.block_manager.new_block(
+        chain.height() + 1,
+        &U256::from_le_bytes(chain.tip_hash().to_byte_array()),
+        &next.header.get_target(),
+    );
+}
+
+pub struct Simulation {
+    nodes: Vec<SimNode>,
+    consensus: ConsensusConfigP2P<bitcoin::Block>,
+    data_dir: PathBuf,
+}
+
+impl Simulation {
+    pub fn new(name: &str, count: usize) -> Self {
+        let data_dir = std::env::temp_dir().join(format!("sickpool-sim-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+
+        let daemons: Vec<MockDaemon> = (0..count)
+            .map(|_| MockDaemon::start(MockChain::regtest()).unwrap())
+            .collect();
+
+        // every daemon serves the same chain, so the nodes agree on the main tip
+        let mut consensus = ProtocolP2P::<Btc>::get_new_pool_config(
+            data_dir.clone().into_boxed_path(),
+            String::from(name),
+            daemons[0].url(),
+            DaemonAuth::None,
+            Network::Regtest,
+            1,
+            1000,
+        )
+        .consensus;
+        consensus.target_1 = MAX_TARGET;
+        // the scripted shares are a second apart whatever their real solve time
+        consensus.diff_adjust_blocks = u32::MAX;
+
+        let nodes = daemons
+            .into_iter()
+            .enumerate()
+            .map(|(i, daemon)| SimNode::start(consensus.clone(), daemon, data_dir.join(i.to_string())))
+            .collect();
+
+        Self {
+            nodes,
+            consensus,
+            data_dir,
+        }
+    }
+
+    // stops the node and starts it again on its data dir, its peers have to be connected again
+    pub fn restart(&mut self, node: usize) {
+        let SimNode {
+            daemon,
+            commands,
+            thread,
+            ..
+        } = self.nodes.remove(node);
+
+        drop(commands);
+        thread.join().unwrap();
+
+        let data_dir = self.data_dir.join(node.to_string());
+        let restarted = SimNode::start(self.consensus.clone(), daemon, data_dir);
+        self.nodes.insert(node, restarted);
+    }
+
+    pub fn connect(&self, from: usize, to: usize) {
+        let to = self.nodes[to].address;
+        self.nodes[from].commands.send(Command::Connect(to)).unwrap();
+    }
+
+    // only the dialing node knows the other's listening address
+    pub fn disconnect(&self, from: usize, to: usize) {
+        let to = self.nodes[to].address;
+        self.nodes[from].commands.send(Command::Disconnect(to)).unwrap();
+    }
+
+    // a share on the node's tip that's yet to be mined, to be tampered with
+    pub fn share_template(&self, node: usize, miner: &MyBtcAddr) -> bitcoin::Block {
+        self.nodes[node].share_template(miner)
+    }
+
+    // mines the share and hands it straight to the node's block manager
+    pub fn process(
+        &self,
+        node: usize,
+        mut share: bitcoin::Block,
+    ) -> Result<TipUpdate, ShareVerificationError> {
+        let protocol = &self.nodes[node].protocol;
+        grind(&mut share, protocol.block_manager.p2p_target());
+
+        protocol
+            .block_manager
+            .process_share(
+                share,
+                &protocol.target_manager,
+                &mut protocol.pplns_window.lock().unwrap(),
+                ShareOrigin::Live,
+            )
+            .map(|(_, update)| update)
+    }
+
+    // mines a share on the node's tip and submits it like its stratum server would
+    pub fn mine(&self, node: usize, miner: &MyBtcAddr) -> U256 {
+        let node = &self.nodes[node];
+
+        let mut share = node.share_template(miner);
+        grind(&mut share, node.protocol.block_manager.p2p_target());
+
+        let hash = share.header.get_hash();
+        node.protocol
+            .handle_share_submit(SubmittingContext::Stratum(node.address), share);
+
+        assert_eq!(node.protocol.block_manager.p2p_tip().hash, hash, "Mined share was rejected");
+        hash
+    }
+
+    pub fn advance_main(&self, node: usize) {
+        self.nodes[node].advance_main();
+    }
+
+    pub fn protocol(&self, node: usize) -> &ProtocolP2P<Btc> {
+        &self.nodes[node].protocol
+    }
+
+    pub fn tip(&self, node: usize) -> U256 {
+        self.nodes[node].protocol.block_manager.p2p_tip().hash
+    }
+
+    pub fn height(&self, node: usize) -> u32 {
+        self.nodes[node]
+            .protocol
+            .block_manager
+            .p2p_tip()
+            .inner
+            .encoded
+            .height
+    }
+
+    pub fn scores(&self, node: usize) -> HashMap<MyBtcAddr, Score> {
+        self.nodes[node]
+            .protocol
+            .pplns_window
+            .lock()
+            .unwrap()
+            .address_scores
+            .clone()
+    }
+
+    pub fn peer_count(&self, node: usize) -> usize {
+        self.nodes[node].protocol.peers.lock().unwrap().len()
+    }
+
+    pub fn wait_for(&self, what: &str, done: impl Fn(&Self) -> bool) {
+        let start = Instant::now();
+        while !done(self) {
+            if start.elapsed() > CONVERGE_TIMEOUT {
+                let tips: Vec<(u32, U256)> = (0..self.nodes.len())
+                    .map(|i| (self.height(i), self.tip(i)))
+                    .collect();
+                panic!("Timed out waiting for {}, tips: {:#?}", what, tips);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // the same tip and the same window on every given node
+    pub fn wait_converged(&self, nodes: &[usize]) {
+        self.wait_for("convergence", |sim| {
+            nodes.windows(2).all(|pair| {
+                sim.tip(pair[0]) == sim.tip(pair[1]) && sim.scores(pair[0]) == sim.scores(pair[1])
+            })
+        });
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        for node in self.nodes.drain(..) {
+            let SimNode {
+                commands, thread, ..
+            } = node;
+
+            drop(commands);
+            let _ = thread.join();
+        }
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn grind(share: &mut bitcoin::Block, target: U256) {
+    while share.header.get_hash() > target {
+        share.header.nonce += 1;
+    }
+}
+
+pub fn miner(i: u8) -> MyBtcAddr {
+    let script = ScriptBuf::from_hex(&format!("0014{}", hex::encode([i; 20]))).unwrap();
+    MyBtcAddr::from_script(&script, Network::Regtest).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::networking::protocol::SubmittingContext;
+
+    use super::{miner, Simulation};
+
+    #[test]
+    fn restart_restores_the_share_chain() {
+        let mut sim = Simulation::new("restart", 1);
+        for _ in 0..3 {
+            sim.mine(0, &miner(1));
+        }
+        let (tip, scores) = (sim.tip(0), sim.scores(0));
+
+        sim.restart(0);
+        assert_eq!(sim.tip(0), tip);
+        assert_eq!(sim.height(0), 3);
+        assert_eq!(sim.scores(0), scores);
+
+        // and it keeps building on it
+        sim.mine(0, &miner(1));
+        assert_eq!(sim.height(0), 4);
+    }
+
+    #[test]
+    fn shares_propagate_and_sync() {
+        let sim = Simulation::new("propagate", 3);
+        for _ in 0..3 {
+            sim.mine(0, &miner(1));
+        }
+
+        // late nodes sync the existing chain when they connect
+        sim.connect(1, 0);
+        sim.wait_converged(&[0, 1]);
+        sim.connect(2, 1);
+        sim.wait_converged(&[0, 1, 2]);
+        assert_eq!(sim.height(2), 3);
+
+        // and new shares are relayed through them
+        sim.mine(2, &miner(2));
+        sim.wait_converged(&[0, 1, 2]);
+        assert_eq!(sim.height(0), 4);
+        assert!(sim.scores(0).contains_key(&miner(2)));
+    }
+
+    #[test]
+    fn shares_ahead_of_main_tip_are_retried() {
+        let sim = Simulation::new("main-lag", 2);
+        sim.advance_main(0);
+        sim.mine(0, &miner(1));
+
+        let share = sim.nodes[0].protocol.block_manager.load_shares(1, 1).unwrap().remove(0);
+        let relay = || {
+            let peer = SubmittingContext::P2P(sim.nodes[0].address);
+            sim.nodes[1].protocol.handle_share_submit(peer, share.clone());
+        };
+
+        // node 1's daemon doesn't have the main block the share builds on yet
+        relay();
+        assert_eq!(sim.height(1), 0);
+
+        sim.advance_main(1);
+        relay();
+        assert_eq!(sim.height(1), 1);
+    }
+
+    #[test]
+    fn tip_changes_are_signalled() {
+        let sim = Simulation::new("signal", 1);
+        let tip_changes = sim.nodes[0].protocol.subscribe_tip_changes();
+        assert!(tip_changes.try_recv().is_err());
+
+        // a listener that's behind gets woken once
+        sim.mine(0, &miner(1));
+        sim.mine(0, &miner(1));
+        assert!(tip_changes.try_recv().is_ok());
+        assert!(tip_changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn partitioned_nodes_converge_after_healing() {
+        let sim = Simulation::new("partition", 4);
+        let linked = |sim: &Simulation| {
+            sim.peer_count(0) == 2
+                && sim.peer_count(1) == 1
+                && sim.peer_count(2) == 2
+                && sim.peer_count(3) == 1
+        };
+
+        // two halves, {0, 1} and {2, 3}, joined by the link from 2 to 0
+        sim.connect(1, 0);
+        sim.connect(3, 2);
+        sim.connect(2, 0);
+        sim.wait_for("links", linked);
+        sim.mine(0, &miner(1));
+        sim.wait_converged(&[0, 1, 2, 3]);
+
+        sim.disconnect(2, 0);
+        sim.wait_for("partition", |sim| sim.peer_count(0) == 1 && sim.peer_count(2) == 1);
+
+        // the first half builds the heavier branch
+        sim.mine(0, &miner(1));
+        sim.wait_converged(&[0, 1]);
+        sim.mine(1, &miner(1));
+        sim.mine(3, &miner(2));
+        sim.wait_converged(&[0, 1]);
+        sim.wait_converged(&[2, 3]);
+        assert_ne!(sim.tip(0), sim.tip(3));
+
+        // node 2 syncs the heavier branch when it reconnects, node 3 reorgs once a new share is relayed to it
+        sim.connect(2, 0);
+        sim.wait_for("links", linked);
+        sim.wait_converged(&[0, 1, 2]);
+        assert_ne!(sim.tip(2), sim.tip(3));
+
+        sim.mine(0, &miner(1));
+        sim.wait_converged(&[0, 1, 2, 3]);
+        assert_eq!(sim.height(3), 4);
+        assert!(!sim.scores(3).contains_key(&miner(2)));
+    }
+}
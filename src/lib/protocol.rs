@@ -1,8 +1,6 @@
 use display_bytes::display_bytes_string;
-use io_arc::IoArc;
 
 use log::warn;
-use mio::net::TcpStream;
 use serde_json::Value;
 use std::{
     net::SocketAddr,
@@ -10,8 +8,9 @@ use std::{
 };
 
 use crate::{
+    framing::{Framing, LineFraming},
     server::Notifier,
-    sickrpc::{ResultOrErr, RpcReqBody, RpcRequest, RpcResponse}, stratum::stratum_v1::Discriminant,
+    sickrpc::{RpcReqBody, RpcRequest, RpcResponse, INVALID_REQUEST, PARSE_ERROR}, stratum::stratum_v1::Discriminant,
 };
 
 // multithreaded
@@ -21,6 +20,8 @@ pub trait Protocol : Send + Sync {
     type Response;
     type ClientContext: std::fmt::Debug + Sync + Send + 'static;
     type ProcessingContext;
+    // how requests are split out of the connection's byte stream
+    type Framing: Framing;
 
     fn new(conf: Self::Config) -> Self;
     fn process_request(
@@ -34,7 +35,7 @@ pub trait Protocol : Send + Sync {
     fn create_client(&self, addr: SocketAddr, notifier: Notifier) -> Option<Self::ClientContext>;
 
     fn delete_client(&self, ctx: Arc<Mutex<Self::ClientContext>>);
-    fn client_conncted(&self, _stream: IoArc<TcpStream>, _ctx: Arc<Mutex<Self::ClientContext>>) {}
+    fn client_conncted(&self, _notifier: Notifier, _ctx: Arc<Mutex<Self::ClientContext>>) {}
 
     fn create_ptx(&self) -> Self::ProcessingContext;
 }
@@ -54,6 +55,7 @@ where
     type Config = UP::Config;
     type ClientContext = UP::ClientContext;
     type ProcessingContext = UP::ProcessingContext;
+    type Framing = LineFraming;
 
     fn new(conf: Self::Config) -> Self {
         Self { up: UP::new(conf) }
@@ -65,38 +67,49 @@ where
         ctx: Arc<Mutex<Self::ClientContext>>,
         ptx: &mut Self::ProcessingContext,
     ) -> Self::Response {
-        let (res, notifs) = match Self::parse_request(&req) {
-            Ok(rpc_request) => {
-                match self
-                    .up
-                    .process_request((rpc_request.method, rpc_request.params), ctx, ptx)
-                {
-                    Ok((res, notifs)) => (RpcResponse::new(rpc_request.id, res), notifs),
-                    Err(e) => (RpcResponse::new_err(rpc_request.id, e), Vec::new()),
+        let mut notifs = Vec::new();
+
+        let res = if Self::is_batch(&req) {
+            match serde_json::from_slice::<Vec<Value>>(&req) {
+                Ok(reqs) if !reqs.is_empty() => {
+                    let responses: Vec<RpcResponse> = reqs
+                        .into_iter()
+                        .filter_map(|req| match serde_json::from_value::<RpcRequest>(req) {
+                            Ok(rpc_request) => {
+                                self.process_rpc_request(rpc_request, ctx.clone(), ptx, &mut notifs)
+                            }
+                            Err(_) => Some(RpcResponse::error(
+                                Value::Null,
+                                Some(String::from("2.0")),
+                                INVALID_REQUEST,
+                                String::from("Invalid Request"),
+                            )),
+                        })
+                        .collect();
+
+                    // a batch of only notifications gets no response
+                    if responses.is_empty() {
+                        None
+                    } else {
+                        Some(serde_json::to_vec(&responses).unwrap())
+                    }
                 }
+                _ => Some(serde_json::to_vec(&Self::bad_request(&req)).unwrap()),
             }
-            Err(e) => {
-                warn!(
-                    "Failed to parse jsonrpc request: {}, req: {:?}",
-                    e,
-                    display_bytes_string(&req)
-                );
-                (
-                    RpcResponse {
-                        id: None,
-                        res_or_err: ResultOrErr::Error((
-                            0,
-                            String::from("Bad JSON RPC request"),
-                            None,
-                        )),
-                    },
-                    Vec::new(),
-                )
+        } else {
+            match Self::parse_request(&req) {
+                Ok(rpc_request) => self
+                    .process_rpc_request(rpc_request, ctx, ptx, &mut notifs)
+                    .map(|res| serde_json::to_vec(&res).unwrap()),
+                Err(_) => Some(serde_json::to_vec(&Self::bad_request(&req)).unwrap()),
             }
         };
 
-        let mut bytes = serde_json::to_vec(&res).unwrap();
-        bytes.push('\n' as u8);
+        let mut bytes = Vec::new();
+        if let Some(mut res) = res {
+            bytes.append(&mut res);
+            bytes.push(b'\n');
+        }
 
         for not in notifs.into_iter() {
             let notification = Self::to_notification(not);
@@ -132,6 +145,76 @@ where
         serde_json::from_slice::<RpcRequest>(req)
     }
 
+    fn is_batch(req: &[u8]) -> bool {
+        req.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[')
+    }
+
+    // none for json rpc 2.0 notifications
+    fn process_rpc_request(
+        &self,
+        rpc_request: RpcRequest,
+        ctx: Arc<Mutex<UP::ClientContext>>,
+        ptx: &mut UP::ProcessingContext,
+        notifs: &mut Vec<RpcReqBody>,
+    ) -> Option<RpcResponse> {
+        let is_notification = rpc_request.is_v2() && rpc_request.id.is_none();
+        let id = rpc_request.id.unwrap_or(Value::Null);
+        let jsonrpc = rpc_request.jsonrpc;
+
+        let res = match self
+            .up
+            .process_request((rpc_request.method, rpc_request.params), ctx, ptx)
+        {
+            Ok((res, mut req_notifs)) => {
+                notifs.append(&mut req_notifs);
+                RpcResponse::new(id, jsonrpc, res)
+            }
+            Err(e) => RpcResponse::new_err(id, jsonrpc, e),
+        };
+
+        if is_notification {
+            None
+        } else {
+            Some(res)
+        }
+    }
+
+    // echoes whatever id and version can still be found in the request
+    fn bad_request(req: &[u8]) -> RpcResponse {
+        warn!(
+            "Failed to parse jsonrpc request: {:?}",
+            display_bytes_string(req)
+        );
+
+        let invalid_v2 = |id| {
+            RpcResponse::error(
+                id,
+                Some(String::from("2.0")),
+                INVALID_REQUEST,
+                String::from("Invalid Request"),
+            )
+        };
+
+        match serde_json::from_slice::<Value>(req) {
+            Ok(Value::Array(_)) => invalid_v2(Value::Null),
+            Ok(req) if req.get("jsonrpc").and_then(Value::as_str) == Some("2.0") => {
+                invalid_v2(req.get("id").cloned().unwrap_or(Value::Null))
+            }
+            Ok(req) => RpcResponse::error(
+                req.get("id").cloned().unwrap_or(Value::Null),
+                None,
+                0,
+                String::from("Bad JSON RPC request"),
+            ),
+            Err(_) => RpcResponse::error(
+                Value::Null,
+                Some(String::from("2.0")),
+                PARSE_ERROR,
+                String::from("Parse error"),
+            ),
+        }
+    }
+
     fn to_notification(req: RpcReqBody) -> RpcRequest {
         let (method, params) = req;
 
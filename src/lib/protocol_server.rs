@@ -2,8 +2,17 @@
 pub mod tests {
 
 
-    use crate::protocol::JsonRpcProtocol;
-    use crate::stratum::stratum_v1::AuthorizeReqParams;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+
+    use crate::framing::LineFraming;
+    use crate::protocol::{JsonRpcProtocol, Protocol};
+    use crate::server::Notifier;
+    use crate::sickrpc::RpcReqBody;
+    use crate::stratum::stratum_v1::StratumV1ErrorCodes;
+    use crate::stratum::stratum_v1::{parse_difficulty_hint, AuthorizeReqParams, SuggestDifficultyReqParams};
     use crate::stratum::stratum_v1::{StratumRequestsBtc, SubmitReqParams};
     use crate::stratum::stratum_v1::StratumV1;
 
@@ -16,7 +25,7 @@ pub mod tests {
         let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
         // JsonRpcProtocol::parse_req(&req).unwrap();
         // assert_eq!(result id: Some(4), method: String::from("mining.submit"), jsonrpc: None })
-        assert_eq!(result.id, Some(4));
+        assert_eq!(result.id, Some(json!(4)));
         assert_eq!(result.method, String::from("mining.submit"));
         assert_eq!(result.jsonrpc, None);
 
@@ -31,11 +40,42 @@ pub mod tests {
                 job_id: 0xbf,
                 extranonce2: 0x00000001,
                 time: 0x504e86ed,
-                nonce: 0xb2957c02
+                nonce: 0xb2957c02,
+                version_bits: None,
             })
         );
     }
 
+    #[test]
+    pub fn submit_version_bits_parse() {
+        let req = r#"{"params": ["slush.miner1", "000000bf", "00000001", "504e86ed", "b2957c02", "04d46000"], "id": 4, "method": "mining.submit"}"#;
+        let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
+
+        let stratum_req = StratumV1::parse_stratum_req(result.method, result.params).unwrap();
+
+        match stratum_req {
+            StratumRequestsBtc::Submit(params) => assert_eq!(params.version_bits, Some(0x04d46000)),
+            _ => panic!("Expected a submit request"),
+        }
+    }
+
+    #[test]
+    pub fn configure_parse() {
+        let req = r#"{"params": [["version-rolling"], {"version-rolling.mask": "1fffe000", "version-rolling.min-bit-count": 2}], "id": 1, "method": "mining.configure"}"#;
+        let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
+
+        let stratum_req = StratumV1::parse_stratum_req(result.method, result.params).unwrap();
+
+        match stratum_req {
+            StratumRequestsBtc::Configure(params) => {
+                assert_eq!(params.extensions, vec![String::from("version-rolling")]);
+                assert_eq!(params.options["version-rolling.mask"], "1fffe000");
+                assert_eq!(params.options["version-rolling.min-bit-count"], 2);
+            }
+            _ => panic!("Expected a configure request"),
+        }
+    }
+
     #[test]
     pub fn authorize_parse() {
         let req =
@@ -45,7 +85,7 @@ pub mod tests {
         // let rpc_result: (String, Token) = rpc_server::parse_req(&req).unwrap();
         let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
 
-        assert_eq!(result.id, Some(2));
+        assert_eq!(result.id, Some(json!(2)));
         assert_eq!(result.method, String::from("mining.authorize"));
         assert_eq!(result.jsonrpc, None);
 
@@ -62,6 +102,120 @@ pub mod tests {
         );
     }
 
+    #[test]
+    pub fn difficulty_hints_parse() {
+        let req = r#"{"params": [1024.5], "id": 3, "method": "mining.suggest_difficulty"}"#;
+        let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
+
+        assert_eq!(
+            StratumV1::parse_stratum_req(result.method, result.params).unwrap(),
+            StratumRequestsBtc::SuggestDifficulty(SuggestDifficultyReqParams { difficulty: 1024.5 })
+        );
+
+        assert_eq!(parse_difficulty_hint("d=1234"), Some(1234.0));
+        assert_eq!(parse_difficulty_hint("x, d=0.5"), Some(0.5));
+        assert_eq!(parse_difficulty_hint("x"), None);
+        assert_eq!(parse_difficulty_hint("d=lots"), None);
+    }
+
+    // echoes the params, fails on "fail"
+    struct Echo;
+
+    impl Protocol for Echo {
+        type Config = ();
+        type Request = RpcReqBody;
+        type Response = Result<(Value, Vec<RpcReqBody>), StratumV1ErrorCodes>;
+        type ClientContext = ();
+        type ProcessingContext = ();
+        type Framing = LineFraming;
+
+        fn new(_conf: ()) -> Self {
+            Echo
+        }
+
+        fn process_request(&self, req: RpcReqBody, _ctx: Arc<Mutex<()>>, _ptx: &mut ()) -> Self::Response {
+            match req.0.as_str() {
+                "fail" => Err(StratumV1ErrorCodes::JobNotFound),
+                _ => Ok((req.1, Vec::new())),
+            }
+        }
+
+        fn create_client(&self, _addr: SocketAddr, _notifier: Notifier) -> Option<()> {
+            Some(())
+        }
+
+        fn delete_client(&self, _ctx: Arc<Mutex<()>>) {}
+
+        fn create_ptx(&self) {}
+    }
+
+    fn process(req: &str) -> Vec<Value> {
+        let protocol = JsonRpcProtocol::<Echo>::new(());
+        let res = protocol.process_request(req.as_bytes().to_vec(), Arc::new(Mutex::new(())), &mut ());
+
+        String::from_utf8(res)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    pub fn ids_are_echoed() {
+        assert_eq!(
+            process(r#"{"params": [1], "id": "abc", "method": "echo"}"#),
+            vec![json!({"id": "abc", "result": [1]})]
+        );
+        assert_eq!(
+            process(r#"{"params": [], "id": null, "method": "fail"}"#),
+            vec![json!({"id": null, "error": [21, "Job not found", null]})]
+        );
+    }
+
+    #[test]
+    pub fn params_are_optional() {
+        assert_eq!(
+            process(r#"{"jsonrpc": "2.0", "id": 6, "method": "echo"}"#),
+            vec![json!({"jsonrpc": "2.0", "id": 6, "result": null})]
+        );
+
+        let req = r#"{"id": 1, "method": "mining.subscribe"}"#;
+        let result = JsonRpcProtocol::<StratumV1>::parse_request(&req.as_bytes()).unwrap();
+        assert_eq!(
+            StratumV1::parse_stratum_req(result.method, result.params).unwrap(),
+            StratumRequestsBtc::Subscribe
+        );
+    }
+
+    #[test]
+    pub fn v2_batches_and_errors() {
+        assert_eq!(
+            process(
+                r#"[{"jsonrpc": "2.0", "params": [], "id": 1, "method": "fail"},
+                    {"jsonrpc": "2.0", "params": [2], "method": "echo"},
+                    {"jsonrpc": "2.0", "params": [3], "id": "3", "method": "echo"},
+                    {"id": 4}]"#
+            ),
+            vec![json!([
+                {"jsonrpc": "2.0", "id": 1, "error": {"code": 21, "message": "Job not found"}},
+                {"jsonrpc": "2.0", "id": "3", "result": [3]},
+                {"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "Invalid Request"}}
+            ])]
+        );
+
+        assert_eq!(
+            process(r#"{"jsonrpc": "2.0", "id": 5}"#),
+            vec![json!({"jsonrpc": "2.0", "id": 5, "error": {"code": -32600, "message": "Invalid Request"}})]
+        );
+        assert_eq!(
+            process(r#"{"jsonrpc": "2.0", "#),
+            vec![json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32700, "message": "Parse error"}})]
+        );
+        assert_eq!(
+            process("[]"),
+            vec![json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "Invalid Request"}})]
+        );
+    }
 }
 
 // TODO: return the rpc correct inheritens and write custom deserializer
@@ -5,7 +5,7 @@ use log::{info, warn};
 use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
 
-use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::io::{ErrorKind, Read, Write};
 
 use std::net::{Shutdown, SocketAddr};
 use std::sync::{Arc, Mutex};
@@ -15,6 +15,7 @@ use std::time::Duration;
 use slab;
 
 use crate::config::ServerConfig;
+use crate::framing::Framing;
 use crate::protocol::Protocol;
 type Slab<T> = slab::Slab<T>;
 
@@ -23,6 +24,11 @@ const SERVER_TOKEN: Token = Token(usize::MAX);
 const TIMEOUT_SEC: u64 = 1;
 const BUFF_CAPACITY: usize = 16 * 1024;
 const INITIAL_CLIENTS_CAPACITY: usize = 1024;
+// a connection that lets this much pile up isn't reading, it's dropped
+const MAX_OUTBOUND_BYTES: usize = 32 * 1024 * 1024;
+
+// a frame to process, with where to send the response and who sent it
+type Request<P> = (Vec<u8>, Notifier, Arc<Mutex<<P as Protocol>::ClientContext>>);
 
 pub struct Server<P: Protocol> {
     pub conf: ServerConfig,
@@ -31,7 +37,8 @@ pub struct Server<P: Protocol> {
     poll: Poll,
     connections: Slab<Connection<P::ClientContext>>,
     protocol: Arc<P>,
-    tx: Sender<(Vec<u8>, IoArc<TcpStream>, Arc<Mutex<P::ClientContext>>)>,
+    // one channel per processing thread, a connection always goes to the same one to keep its responses in order
+    txs: Vec<Sender<Request<P>>>,
     // processing: Vec<JoinHandle<>>
 }
 
@@ -42,7 +49,9 @@ pub struct Connection<T> {
     // responded: usize,
     addr: SocketAddr,
     stream: IoArc<TcpStream>,
-    reader: BufReader<IoArc<TcpStream>>,
+    notifier: Notifier,
+    // received bytes that don't form a complete frame yet
+    read_buf: Vec<u8>,
     protocol_context: Arc<Mutex<T>>,
     connected: bool,
 }
@@ -53,21 +62,86 @@ impl<T> std::fmt::Display for Connection<T> {
     }
 }
 
-pub fn respond(mut stream: &TcpStream, msg: &[u8]) {
-    // self.add_client_context(token, t);
-    if let Err(e) = stream.write_all(msg) {
-        warn!("error responding: {}", e);
-    }
-    stream.flush().unwrap()
+// bytes the socket didn't take yet, frames are only appended whole so writers can't interleave
+#[derive(Debug, Default)]
+struct Outbound {
+    buf: Vec<u8>,
+    written: usize,
+    closed: bool,
 }
 
-// pretty useless rn
+// every write to a connection goes through here, in order
 #[derive(Debug, Clone)]
-pub struct Notifier(pub(crate) IoArc<TcpStream>);
+pub struct Notifier {
+    stream: IoArc<TcpStream>,
+    outbound: Arc<Mutex<Outbound>>,
+}
 
 impl Notifier {
+    fn new(stream: IoArc<TcpStream>) -> Self {
+        Self {
+            stream,
+            outbound: Arc::new(Mutex::new(Outbound::default())),
+        }
+    }
+
+    // writes what the socket takes now, the rest is sent on the next writable event
     pub fn notify(&self, msg: &[u8]) {
-        respond(self.0.as_ref(), msg)
+        let mut outbound = self.outbound.lock().unwrap();
+        if outbound.closed {
+            return;
+        }
+
+        outbound.buf.extend_from_slice(msg);
+        self.write_pending(&mut outbound);
+
+        if outbound.buf.len() - outbound.written > MAX_OUTBOUND_BYTES {
+            warn!("Outbound buffer full, dropping slow connection");
+            self.close(&mut outbound);
+        }
+    }
+
+    fn flush(&self) {
+        let mut outbound = self.outbound.lock().unwrap();
+        if !outbound.closed {
+            self.write_pending(&mut outbound);
+        }
+    }
+
+    fn write_pending(&self, outbound: &mut Outbound) {
+        let mut stream: &TcpStream = self.stream.as_ref();
+
+        while outbound.written < outbound.buf.len() {
+            match stream.write(&outbound.buf[outbound.written..]) {
+                Ok(n) => outbound.written += n,
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        warn!("error responding: {}", e);
+                        self.close(outbound);
+                        return;
+                    }
+                },
+            }
+        }
+
+        if outbound.written == outbound.buf.len() {
+            outbound.buf.clear();
+            outbound.written = 0;
+        } else if outbound.written >= BUFF_CAPACITY {
+            let written = outbound.written;
+            outbound.buf.drain(..written);
+            outbound.written = 0;
+        }
+    }
+
+    // the poll loop sees the shutdown and disconnects the client
+    fn close(&self, outbound: &mut Outbound) {
+        outbound.closed = true;
+        outbound.buf = Vec::new();
+        outbound.written = 0;
+        let _ = self.stream.as_ref().shutdown(Shutdown::Both);
     }
 }
 
@@ -82,27 +156,23 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
 
         info!("Started server on {:?}", conf.address);
 
-        let (tx, rx) = flume::unbounded();
-
-        for _ in 0..conf.processing_threads {
-            let rx = rx.clone();
+        let threads = conf.processing_threads.max(1) as usize;
+        let mut txs = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let (tx, rx) = flume::unbounded::<Request<P>>();
+            txs.push(tx);
             let protocol = protocol.clone();
 
             thread::spawn(move || {
                 let mut ptx = protocol.create_ptx();
 
-                loop {
-                    let (req, writer, ctx): (
-                        Vec<u8>,
-                        IoArc<TcpStream>,
-                        Arc<Mutex<P::ClientContext>>,
-                    ) = rx.recv().unwrap();
-
+                // stops once the server is dropped
+                while let Ok((req, writer, ctx)) = rx.recv() {
                     // let now = Instant::now();
 
                     let protocol_resp = protocol.process_request(req, ctx, &mut ptx);
 
-                    respond(writer.as_ref(), protocol_resp.as_ref());
+                    writer.notify(protocol_resp.as_ref());
                     // let elapsed = now.elapsed().as_micros();
                     // info!(
                     //     "Processed response: {:?}, in {}us",
@@ -120,7 +190,7 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
             poll,
             connections: Slab::with_capacity(INITIAL_CLIENTS_CAPACITY),
             conf,
-            tx,
+            txs,
         }
     }
 
@@ -139,8 +209,10 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
 
     pub fn process_requests(&mut self) {
         let requests = self.read_requests();
-        for (req, writer, ctx) in requests.into_iter() {
-            self.tx.send((req, writer, ctx)).unwrap();
+        for (token, req, writer, ctx) in requests.into_iter() {
+            self.txs[token.0 % self.txs.len()]
+                .send((req, writer, ctx))
+                .unwrap();
         }
 
         // if !rem_cons.is_empty() {
@@ -150,6 +222,23 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
         // }
     }
 
+    // closes the connection with the given address, false if there is none
+    pub fn disconnect_address(&mut self, addr: SocketAddr) -> bool {
+        let token = self
+            .connections
+            .iter()
+            .find(|(_token, con)| con.addr == addr)
+            .map(|(token, _con)| Token(token));
+
+        match token {
+            Some(token) => {
+                self.disconnect(token);
+                true
+            }
+            None => false,
+        }
+    }
+
     // also closes the underlying stream
     fn disconnect(&mut self, token: Token) {
         let cn = match self.connections.try_remove(token.0) {
@@ -205,9 +294,9 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
         }
 
         let stream = IoArc::new(stream);
-        let notifier = Notifier(stream.clone());
+        let notifier = Notifier::new(stream.clone());
 
-        let ctx = match self.protocol.create_client(addr, notifier) {
+        let ctx = match self.protocol.create_client(addr, notifier.clone()) {
             Some(k) => k,
             None => {
                 return None;
@@ -216,9 +305,10 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
 
         let con = vacant_entry.insert(Connection {
             addr,
-            reader: BufReader::with_capacity(BUFF_CAPACITY, stream.clone()),
+            read_buf: Vec::with_capacity(BUFF_CAPACITY),
             protocol_context: Arc::new(Mutex::new(ctx)),
             stream,
+            notifier,
             connected: true,
         });
 
@@ -229,7 +319,7 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
     // (requests, new connections, removed_connections)
     pub fn read_requests(
         &mut self,
-    ) -> Vec<(Vec<u8>, IoArc<TcpStream>, Arc<Mutex<P::ClientContext>>)> {
+    ) -> Vec<(Token, Vec<u8>, Notifier, Arc<Mutex<P::ClientContext>>)> {
         let mut events = Events::with_capacity(128);
         let mut lines = Vec::<_>::with_capacity(128);
         let mut new_cons = Vec::new();
@@ -254,28 +344,24 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
             }
 
             if event.is_readable() {
-                loop {
-                    match self.read_ready_line(token) {
-                        Ok(k) => match k {
-                            Some(line) => {
-                                let connection = &self.connections[token.0];
-                                lines.push((
-                                    line,
-                                    connection.stream.clone(),
-                                    connection.protocol_context.clone(),
-                                ))
-                            }
-                            None => break,
-                        },
-                        Err(_e) => {
-                            removed_cons.push(token);
-                            break;
+                match self.read_ready_frames(token) {
+                    Ok(frames) => {
+                        let connection = &self.connections[token.0];
+                        for frame in frames {
+                            lines.push((
+                                token,
+                                frame,
+                                connection.notifier.clone(),
+                                connection.protocol_context.clone(),
+                            ))
                         }
                     }
+                    Err(_e) => {
+                        removed_cons.push(token);
+                    }
                 }
             }
 
-            // TODO: buffer failed writes
             if event.is_writable() {
                 if let Some(con) = self.connections.get_mut(token.0) {
                     if !con.connected {
@@ -286,7 +372,7 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
                                 info!("Connection to {} has been established!", con.addr);
 
                                 self.protocol.client_conncted(
-                                    con.stream.clone(),
+                                    con.notifier.clone(),
                                     con.protocol_context.clone(),
                                 );
                             }
@@ -295,6 +381,7 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
                             }
                         }
                     }
+                    con.notifier.flush();
                 }
             }
         }
@@ -334,7 +421,8 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
     //     }
     // }
 
-    fn read_ready_line(&mut self, token: Token) -> Result<Option<Vec<u8>>, ()> {
+    // reads everything available and splits it using the protocol's framing
+    fn read_ready_frames(&mut self, token: Token) -> Result<Vec<Vec<u8>>, ()> {
         let conn = match self.connections.get_mut(token.0) {
             Some(k) => k,
             None => {
@@ -343,31 +431,110 @@ impl<P: Protocol<Request = Vec<u8>, Response = Vec<u8>> + Send + Sync + 'static>
             }
         };
 
-        let mut line = Vec::new();
-        match conn.reader.read_until('\n' as u8, &mut line) {
-            // disconnect. EOF
-            Ok(0) => {
-                warn!("Client EOF: {}", &conn);
-                return Err(());
-            }
-            Ok(_) => {
-                // remove delimiter
-                line.pop();
-                return Ok(Some(line));
-            }
-            Err(e) => {
-                match e.kind() {
-                    // FINISHED READING or Interrupted
-                    ErrorKind::WouldBlock | ErrorKind::Interrupted => Ok(None),
-                    // Other errors we'll consider fatal.
-                    _ => {
-                        warn!("Error reading: {}", e);
-                        return Err(());
+        let mut chunk = [0u8; BUFF_CAPACITY];
+        let mut stream: &TcpStream = conn.stream.as_ref();
+        loop {
+            match stream.read(&mut chunk) {
+                // disconnect. EOF
+                Ok(0) => {
+                    warn!("Client EOF: {}", &conn);
+                    return Err(());
+                }
+                Ok(n) => conn.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    match e.kind() {
+                        // FINISHED READING
+                        ErrorKind::WouldBlock => break,
+                        ErrorKind::Interrupted => continue,
+                        // Other errors we'll consider fatal.
+                        _ => {
+                            warn!("Error reading: {}", e);
+                            return Err(());
+                        }
                     }
                 }
             }
         }
+
+        let mut frames = Vec::new();
+        loop {
+            match P::Framing::decode(&mut conn.read_buf) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Bad frame from {}: {:?}", &conn, e);
+                    return Err(());
+                }
+            }
+        }
+        Ok(frames)
     }
 }
 
 // WHEN A NEW JOB COMES, processing threads need to first update their context, then we can notify the clients, shares that are being processed whilst the new job was received are acceptable
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use io_arc::IoArc;
+    use mio::net::TcpStream;
+
+    use super::{Notifier, MAX_OUTBOUND_BYTES};
+
+    pub(crate) fn notifier_pair() -> (Notifier, std::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reader = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        (Notifier::new(IoArc::new(TcpStream::from_std(stream))), reader)
+    }
+
+    #[test]
+    fn queued_frames_arrive_in_order() {
+        let (notifier, mut reader) = notifier_pair();
+        let frames: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 1024 * 1024]).collect();
+
+        // far more than the socket takes at once
+        for frame in &frames {
+            notifier.notify(frame);
+        }
+
+        let expected = frames.concat();
+        reader.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let reading = std::thread::spawn(move || {
+            let mut received = vec![0u8; expected.len()];
+            reader.read_exact(&mut received).unwrap();
+            received == expected
+        });
+
+        // what the event loop would do on every writable event
+        let start = Instant::now();
+        while !reading.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Queued frames weren't flushed");
+            notifier.flush();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(reading.join().unwrap());
+    }
+
+    #[test]
+    fn slow_readers_are_dropped() {
+        let (notifier, _reader) = notifier_pair();
+        let frame = vec![0u8; 1024 * 1024];
+
+        for _ in 0..MAX_OUTBOUND_BYTES / frame.len() {
+            notifier.notify(&frame);
+        }
+        assert!(!notifier.outbound.lock().unwrap().closed);
+
+        // some of it sits in the socket's own buffers
+        for _ in 0..MAX_OUTBOUND_BYTES / frame.len() {
+            notifier.notify(&frame);
+        }
+        assert!(notifier.outbound.lock().unwrap().closed);
+    }
+}
@@ -1,25 +1,47 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 use crate::stratum::stratum_v1::Discriminant;
 
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct RpcRequest {
     // pub method: String,
+    // optional in json rpc 2.0, null when missing
+    #[serde(default)]
     pub params: Value,
-    pub id: Option<u64>,
+    // any json value, none only when it's missing (a json rpc 2.0 notification)
+    #[serde(default, deserialize_with = "present_id")]
+    pub id: Option<Value>,
     pub method: String,
     #[serde(skip_serializing_if="Option::is_none")]
     pub jsonrpc: Option<String>,
 }
 
+// a null id is still an id, unlike a missing one
+fn present_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+impl RpcRequest {
+    pub fn is_v2(&self) -> bool {
+        is_v2(&self.jsonrpc)
+    }
+}
+
+pub fn is_v2(jsonrpc: &Option<String>) -> bool {
+    jsonrpc.as_deref() == Some("2.0")
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct RpcResponse {
-    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub jsonrpc: Option<String>,
+    pub id: Value,
     #[serde(flatten)]
     pub res_or_err: ResultOrErr,
-    // pub jsonrpc: String
 }
 pub type RpcReqBody = (String, Value);
 
@@ -36,21 +58,48 @@ impl std::fmt::Display for RpcResponse {
 #[serde(rename_all = "lowercase")]
 pub enum ResultOrErr {
     Result(Value),
-    Error((u32, String, Option<Value>)),
+    Error(RpcError),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum RpcError {
+    // stratum's [code, message, data]
+    Tuple((i64, String, Option<Value>)),
+    // json rpc 2.0
+    Object {
+        code: i64,
+        message: String,
+        #[serde(default, skip_serializing_if="Option::is_none")]
+        data: Option<Value>,
+    },
 }
 
 impl RpcResponse {
-    pub fn new(id: Option<u64>, res: Value) -> RpcResponse {
+    // 2.0 requests get a 2.0 response
+    pub fn new(id: Value, jsonrpc: Option<String>, res: Value) -> RpcResponse {
         RpcResponse {
+            jsonrpc: if is_v2(&jsonrpc) { jsonrpc } else { None },
             id,
             res_or_err: ResultOrErr::Result(res),
         }
     }
 
-    pub fn new_err<E: std::fmt::Display + Discriminant>(id: Option<u64>, e_code: E) -> RpcResponse {
+    pub fn new_err<E: std::fmt::Display + Discriminant>(id: Value, jsonrpc: Option<String>, e_code: E) -> RpcResponse {
+        Self::error(id, jsonrpc, e_code.discriminant() as i64, e_code.to_string())
+    }
+
+    pub fn error(id: Value, jsonrpc: Option<String>, code: i64, message: String) -> RpcResponse {
+        let (jsonrpc, err) = if is_v2(&jsonrpc) {
+            (jsonrpc, RpcError::Object { code, message, data: None })
+        } else {
+            (None, RpcError::Tuple((code, message, None)))
+        };
+
         RpcResponse {
+            jsonrpc,
             id,
-            res_or_err: ResultOrErr::Error((e_code.discriminant(), e_code.to_string(), None)),
+            res_or_err: ResultOrErr::Error(err),
         }
     }
 }
@@ -1,20 +1,33 @@
-use std::{collections::{HashSet, HashMap}, net::SocketAddr};
+use std::{collections::{HashSet, HashMap}, net::SocketAddr, time::Instant};
 
 
 
 use crypto_bigint::U256;
 
-use crate::{server::Notifier, p2p::duplicate_checker::DuplicateHashChecker};
+use crate::{server::Notifier, p2p::{duplicate_checker::DuplicateHashChecker, networking::difficulty::get_target_from_diff_units}};
+
+use super::vardiff::{Vardiff, VardiffConfig};
+
+// stale jobs are rejected anyway, only recent submissions can be duplicates
+const SUBMITTED_SHARES_CAPACITY: usize = 1024;
 
 #[derive(Debug)]
 pub struct StratumClient {
     pub address: SocketAddr,
     pub notifier: Notifier,
     pub extra_nonce: u32,
+    // a single worker, the difficulty is per connection
     pub authorized_workers: HashMap<String, String>,
     pub submitted_shares: DuplicateHashChecker,
     pub target: U256,
-    pub subscription_key: Option<usize>
+    // still accepted until the next retarget, for shares that were in flight when the difficulty changed
+    pub previous_target: Option<U256>,
+    pub vardiff: Vardiff,
+    // from mining.suggest_difficulty, the starting difficulty if the worker gives no hint of its own
+    pub suggested_diff_units: Option<u64>,
+    pub subscription_key: Option<usize>,
+    // the version bits the client negotiated to roll, none by default
+    pub version_mask: u32,
 }
 
 impl StratumClient {
@@ -23,10 +36,109 @@ impl StratumClient {
             notifier,
             extra_nonce: id,
             target: U256::ZERO,
+            previous_target: None,
+            vardiff: Vardiff::new(0, Instant::now()),
+            suggested_diff_units: None,
             authorized_workers: HashMap::new(),
-            submitted_shares: DuplicateHashChecker::default(),
+            submitted_shares: DuplicateHashChecker::new(SUBMITTED_SHARES_CAPACITY),
             subscription_key: None,
+            version_mask: 0,
             address,
         }
     }
+
+    pub fn set_diff_units(&mut self, diff_units: u64, diff1: &U256) {
+        self.target = get_target_from_diff_units(diff_units, diff1);
+        self.previous_target = None;
+        self.vardiff = Vardiff::new(diff_units, Instant::now());
+    }
+
+    pub fn meets_target(&self, hash: &U256) -> bool {
+        hash <= &self.target || self.previous_target.is_some_and(|target| hash <= &target)
+    }
+
+    // the new difficulty if the client's share rate moved it, clients get one once they authorize
+    pub fn retarget(&mut self, conf: &VardiffConfig, diff1: &U256, now: Instant) -> Option<u64> {
+        if self.authorized_workers.is_empty() {
+            return None;
+        }
+
+        let old = self.vardiff.diff_units;
+        let new = self.vardiff.retarget(conf, now)?;
+
+        self.previous_target = None;
+        if new == old {
+            return None;
+        }
+
+        self.previous_target = Some(self.target);
+        self.target = get_target_from_diff_units(new, diff1);
+        Some(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        coins::{bitcoin::Btc, coin::Coin},
+        p2p::networking::difficulty::get_target_from_diff_units,
+        server::tests::notifier_pair,
+        stratum::vardiff::VardiffConfig,
+    };
+
+    use super::StratumClient;
+
+    fn config() -> VardiffConfig {
+        VardiffConfig {
+            target_share_interval_ms: 10_000,
+            retarget_interval_ms: 60_000,
+            min_diff_units: 100,
+            max_diff_units: 100_000,
+        }
+    }
+
+    fn client() -> StratumClient {
+        let (notifier, _) = notifier_pair();
+        StratumClient::new(notifier, 0, "127.0.0.1:1".parse().unwrap())
+    }
+
+    #[test]
+    fn unauthorized_clients_are_not_retargeted() {
+        let mut client = client();
+        let later = Instant::now() + Duration::from_secs(120);
+
+        assert_eq!(client.retarget(&config(), &Btc::DIFF1, later), None);
+        assert_eq!(client.vardiff.diff_units, 0);
+    }
+
+    #[test]
+    fn previous_target_is_accepted_until_the_next_retarget() {
+        let mut client = client();
+        client
+            .authorized_workers
+            .insert(String::from("worker"), String::from("x"));
+        client.set_diff_units(1000, &Btc::DIFF1);
+        let old_target = client.target;
+
+        // a flood of shares makes it harder
+        let start = Instant::now();
+        for _ in 0..1000 {
+            client.vardiff.add_share();
+        }
+        let now = start + Duration::from_secs(60);
+        assert_eq!(client.retarget(&config(), &Btc::DIFF1, now), Some(4000));
+        assert_eq!(client.target, get_target_from_diff_units(4000, &Btc::DIFF1));
+
+        // shares in flight at the old difficulty still count
+        assert!(client.meets_target(&old_target));
+
+        // the right rate keeps the difficulty, the old one is gone with the next retarget
+        for _ in 0..6 {
+            client.vardiff.add_share();
+        }
+        assert_eq!(client.retarget(&config(), &Btc::DIFF1, now + Duration::from_secs(60)), None);
+        assert!(!client.meets_target(&old_target));
+    }
 }
@@ -38,7 +38,7 @@ where
 
             if hash <= job.target {
                 ShareResult::Block(hash)
-            } else if hash <= client.target {
+            } else if client.meets_target(&hash) {
                 ShareResult::Valid(hash)
             } else {
                 ShareResult::Invalid()
@@ -2,9 +2,19 @@ use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
+use super::{job_fetcher::DaemonAuth, vardiff::VardiffConfig};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StratumConfig {
     pub rpc_url: String,
+    #[serde(default)]
+    pub rpc_auth: DaemonAuth,
     pub job_poll_interval_ms: u64,
+    // the starting difficulty of every client
     pub default_diff_units: u64,
+    #[serde(default)]
+    pub vardiff: VardiffConfig,
+    // bitcoind's zmqpubhashblock endpoint, new blocks are picked up without waiting for a poll
+    #[serde(default)]
+    pub zmq_hashblock: Option<String>,
 }
\ No newline at end of file
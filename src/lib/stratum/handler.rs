@@ -15,5 +15,5 @@ pub trait StratumHandler<C: Coin> {
         share: &C::BlockT,
         hash: U256,
     );
-    fn on_new_block(&self, height: u32, block_hash: &U256);
+    fn on_new_block(&self, height: u32, block_hash: &U256, target: &U256);
 }
\ No newline at end of file
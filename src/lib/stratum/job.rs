@@ -2,6 +2,7 @@ use bitcoin::{
     consensus::Encodable, hash_types::TxMerkleNode, hashes::Hash,
 };
 use crypto_bigint::{ U256};
+use std::time::Instant;
 
 use itertools::Itertools;
 
@@ -22,7 +23,12 @@ pub trait Job<T, E>: Clone + std::fmt::Debug {
     // in job and not block because of merkle steps
     fn update_fields(&mut self, params: &Self::SubmitParams);
 
-    fn get_broadcast_message(id: u32, fetch: &BlockFetch<T>, merkle_steps: &Vec<[u8; 32]>) -> E;
+    fn get_broadcast_message(
+        id: u32,
+        fetch: &BlockFetch<T>,
+        merkle_steps: &Vec<[u8; 32]>,
+        clean_jobs: bool,
+    ) -> E;
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +39,9 @@ pub struct JobBtc<BlockT, MessageT> {
     pub height: u32,
     pub reward: u64,
     pub merkle_steps: Vec<[u8; 32]>,
+    // the template's version, the block's can be rolled by the last submit
+    pub version: u32,
+    pub created: Instant,
     // todo: bytes?
     pub broadcast_message: MessageT,
 }
@@ -42,14 +51,17 @@ where
     // bitcoin::Block: Block<Address>,
     JobBtc<bitcoin::Block, E>: Job<bitcoin::Block, E>,
 {
-    pub fn new(id: u32, fetch: BlockFetch<bitcoin::Block>) -> Self {
+    // clean_jobs tells miners to drop their work on older jobs, only needed on a new main tip
+    pub fn new(id: u32, fetch: BlockFetch<bitcoin::Block>, clean_jobs: bool) -> Self {
         let target = fetch.block.header.get_target();
         // TODO: avoid clone, its unnececsergfsdf
         let merkle_steps = calc_merkle_steps(fetch.tx_hashes.clone());
         JobBtc {
             id,
             target,
-            broadcast_message: Self::get_broadcast_message(id, &fetch, &merkle_steps),
+            broadcast_message: Self::get_broadcast_message(id, &fetch, &merkle_steps, clean_jobs),
+            created: Instant::now(),
+            version: fetch.block.header.get_version(),
             block: fetch.block,
             height: fetch.height,
             reward: fetch.reward,
@@ -72,18 +84,22 @@ where
 }
 
 impl Job<bitcoin::Block, RpcReqBody> for JobBtc<bitcoin::Block, RpcReqBody> {
-    type SubmitParams = (SubmitReqParams, u32); //extra nonce 1
+    type SubmitParams = (SubmitReqParams, u32, u32); //extra nonce 1, version rolling mask
 
     fn get_broadcast_message(
         id: u32,
         fetch: &BlockFetch<bitcoin::Block>,
         merkle_steps: &Vec<[u8; 32]>,
+        clean_jobs: bool,
     ) -> RpcReqBody {
         let header = fetch.block.header;
 
+        // miners hash the coinbase for the txid, which never includes the witness
+        let mut coinbase = fetch.block.txdata[0].clone();
+        coinbase.input[0].witness.clear();
+
         let mut cb_bytes = Vec::new();
-        let _res: &Result<usize, std::io::Error> =
-            &fetch.block.txdata[0].consensus_encode(&mut cb_bytes);
+        let _res: &Result<usize, std::io::Error> = &coinbase.consensus_encode(&mut cb_bytes);
 
         let script_size = fetch.block.txdata[0].input[0].script_sig.len();
         let prev_hash_str = Self::format_prev_hash(&header.get_prev());
@@ -102,16 +118,20 @@ impl Job<bitcoin::Block, RpcReqBody> for JobBtc<bitcoin::Block, RpcReqBody> {
                 hex::encode(header.version.to_consensus().to_be_bytes()),
                 hex::encode(header.bits.to_consensus().to_be_bytes()),
                 hex::encode(header.time.to_be_bytes()),
-                "true"
+                clean_jobs
             ]),
         )
     }
 
-    fn update_fields(&mut self, params: &(SubmitReqParams, u32)) {
-        let (params, extra_nonce1) = params;
+    fn update_fields(&mut self, params: &(SubmitReqParams, u32, u32)) {
+        let (params, extra_nonce1, version_mask) = params;
         self.block.header.nonce = params.nonce;
         self.block.header.time = params.time;
 
+        let version_bits = params.version_bits.unwrap_or(self.version);
+        let version = (self.version & !version_mask) | (version_bits & version_mask);
+        self.block.header.version = bitcoin::block::Version::from_consensus(version as i32);
+
         let extra_nonce = ((*extra_nonce1).to_be() as u64) + ((params.extranonce2 as u64) << 32);
         // let extra_nonce = 1u64;
 
@@ -181,9 +201,25 @@ mod tests {
 
     use crypto_bigint::U256;
 
-    use crate::{sickrpc::RpcReqBody, stratum::job::build_merkle_root_from_steps};
-
-    use super::{calc_merkle_steps, JobBtc};
+    use bitcoin::consensus::deserialize;
+
+    use crate::{
+        p2p::networking::{
+            bitcoin::tests::{sample_payout_script, segwit_template},
+            block::Block,
+            pplns::MAX_SCORE,
+            share::CoinbaseEncodedP2P,
+        },
+        sickrpc::RpcReqBody,
+        stratum::{
+            header::BlockHeader,
+            job::build_merkle_root_from_steps,
+            job_fetcher::BlockFetch,
+            stratum_v1::{SubmitReqParams, VERSION_ROLLING_MASK},
+        },
+    };
+
+    use super::{calc_merkle_steps, Job, JobBtc};
 
     fn hex_to_arr<const N: usize>(s: &str) -> [u8; N] {
         hex::decode(s).unwrap().try_into().unwrap()
@@ -242,6 +278,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn segwit_coinbase_parts_are_legacy() {
+        let template = segwit_template();
+        let (block, tx_hashes) = bitcoin::Block::from_block_template(
+            &template,
+            [(sample_payout_script(), MAX_SCORE)].into_iter(),
+            CoinbaseEncodedP2P::default(),
+        );
+        let job = JobBtc::<bitcoin::Block, RpcReqBody>::new(
+            0,
+            BlockFetch {
+                block,
+                tx_hashes,
+                height: template.height as u32,
+                reward: template.coinbase_value.to_sat(),
+            },
+            true,
+        );
+
+        let params = &job.broadcast_message.1;
+        let mut coinbase_bytes = hex::decode(params[2].as_str().unwrap()).unwrap();
+        coinbase_bytes.extend_from_slice(&[0u8; 8]);
+        coinbase_bytes.extend(hex::decode(params[3].as_str().unwrap()).unwrap());
+
+        // what the miners hash must decode as the coinbase without its witness
+        let coinbase: bitcoin::Transaction = deserialize(&coinbase_bytes).unwrap();
+        assert!(coinbase.input[0].witness.is_empty());
+        assert_eq!(coinbase.output, job.block.txdata[0].output);
+    }
+
+    #[test]
+    fn rolled_version_is_masked() {
+        let template = segwit_template();
+        let (block, tx_hashes) = bitcoin::Block::from_block_template(
+            &template,
+            [(sample_payout_script(), MAX_SCORE)].into_iter(),
+            CoinbaseEncodedP2P::default(),
+        );
+        let mut job = JobBtc::<bitcoin::Block, RpcReqBody>::new(
+            0,
+            BlockFetch {
+                block,
+                tx_hashes,
+                height: template.height as u32,
+                reward: template.coinbase_value.to_sat(),
+            },
+            true,
+        );
+        let submit = |version_bits| SubmitReqParams {
+            worker_name: String::new(),
+            job_id: 0,
+            extranonce2: 0,
+            time: 0,
+            nonce: 0,
+            version_bits,
+        };
+
+        // bits outside the negotiated mask are ignored
+        job.update_fields(&(submit(Some(0xffffffff)), 1, VERSION_ROLLING_MASK));
+        assert_eq!(
+            job.block.header.get_version(),
+            job.version | VERSION_ROLLING_MASK
+        );
+
+        // and a later submit without rolling gets the template's version back
+        job.update_fields(&(submit(None), 1, VERSION_ROLLING_MASK));
+        assert_eq!(job.block.header.get_version(), job.version);
+
+        job.update_fields(&(submit(Some(0xffffffff)), 1, 0));
+        assert_eq!(job.block.header.get_version(), job.version);
+    }
+
     #[test]
     fn test_format_prev_hash() {
         let res = JobBtc::<bitcoin::Block, RpcReqBody>::format_prev_hash(&U256::from_be_hex(
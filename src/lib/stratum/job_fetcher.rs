@@ -1,4 +1,11 @@
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    fmt::Debug,
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
 use bitcoin::{hashes::Hash, BlockHash, ScriptBuf};
 use bitcoincore_rpc::{
@@ -8,9 +15,20 @@ use bitcoincore_rpc::{
     Auth, RpcApi,
 };
 use crypto_bigint::{Encoding, U256};
+use flume::{Sender, TrySendError};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::p2p::networking::{block::Block, share::CoinbaseEncodedP2P};
 
+use super::zmq::{ZmqSubscriber, HASHBLOCK_TOPIC};
+
+// a long poll that fails sooner than this didn't wait on the daemon, it couldn't reach it
+const LONG_POLL_MIN_WAIT: Duration = Duration::from_secs(1);
+// bitcoind's publishers send nothing between blocks, so a long silence is worth a new connection
+pub const ZMQ_SILENCE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
 pub struct BlockFetch<BlockT> {
     pub block: BlockT,
     pub tx_hashes: Vec<[u8; 32]>,
@@ -18,9 +36,20 @@ pub struct BlockFetch<BlockT> {
     pub reward: u64,
 }
 
+// how to authenticate to the daemon's rpc
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DaemonAuth {
+    #[default]
+    None,
+    Cookie { path: PathBuf },
+    UserPass { user: String, password: String },
+}
+
 pub trait BlockFetcher<BlockT: Block>: Send + Sync + Debug + Sized {
     type ErrorT: std::fmt::Display + std::fmt::Debug + Sized;
-    fn new(url: &str) -> Result<Self, Self::ErrorT>;
+    // fails if the daemon can't be reached with the given credentials
+    fn new(url: &str, auth: &DaemonAuth) -> Result<Self, Self::ErrorT>;
     fn fetch_blocktemplate(
         &self,
         vout: impl Iterator<Item = (BlockT::Script, u64)>,
@@ -30,6 +59,97 @@ pub trait BlockFetcher<BlockT: Block>: Send + Sync + Debug + Sized {
 
     fn fetch_block(&self, hash: &U256) -> Result<BlockT, bitcoincore_rpc::Error>;
     fn get_best_blockhash(&self) -> Result<U256, bitcoincore_rpc::Error>;
+    // the chain the daemon is running on
+    fn get_network(&self) -> Result<BlockT::Network, bitcoincore_rpc::Error>;
+    // the longpollid of the template after the given one, right away without one.
+    // none if the daemon doesn't support long polling
+    fn long_poll(&self, longpollid: Option<&str>) -> Result<Option<String>, bitcoincore_rpc::Error>;
+}
+
+// wakes the job manager whenever the daemon's template changes, until the daemon turns out not to support it.
+// active is cleared whenever the templates aren't followed, so interval polling can take over
+pub fn long_poll_templates<BlockT: Block, Fetcher: BlockFetcher<BlockT>>(
+    fetcher: &Fetcher,
+    wake: &Sender<()>,
+    active: &AtomicBool,
+    retry_interval: Duration,
+) {
+    let mut longpollid = None;
+
+    loop {
+        let start = Instant::now();
+        match fetcher.long_poll(longpollid.as_deref()) {
+            Ok(Some(id)) => {
+                if let Err(TrySendError::Disconnected(_)) = wake.try_send(()) {
+                    break;
+                }
+                if !active.swap(true, Ordering::Relaxed) {
+                    info!("Long polling block templates");
+                }
+                longpollid = Some(id);
+            }
+            Ok(None) => {
+                info!("Daemon doesn't support long polling, polling templates every {:?}", retry_interval);
+                break;
+            }
+            // the rpc client times out long polls that wait for long, just ask again
+            Err(_) if longpollid.is_some() && start.elapsed() >= LONG_POLL_MIN_WAIT => {}
+            Err(e) => {
+                warn!("Failed to long poll block template: {}", e);
+                active.store(false, Ordering::Relaxed);
+                longpollid = None;
+                thread::sleep(retry_interval);
+            }
+        }
+    }
+    active.store(false, Ordering::Relaxed);
+}
+
+// wakes the job manager on every block the daemon publishes, reconnecting whenever the publisher goes away
+// or stays silent for longer than the timeout
+pub fn subscribe_new_blocks(
+    endpoint: &str,
+    wake: &Sender<()>,
+    retry_interval: Duration,
+    silence_timeout: Duration,
+) {
+    loop {
+        match ZmqSubscriber::connect(endpoint, HASHBLOCK_TOPIC, silence_timeout) {
+            Ok(mut subscriber) => {
+                info!("Subscribed to new blocks at {}", endpoint);
+                loop {
+                    match subscriber.recv() {
+                        Ok(message) => {
+                            if message.first().map(Vec::as_slice) != Some(HASHBLOCK_TOPIC) {
+                                continue;
+                            }
+                            if let Err(TrySendError::Disconnected(_)) = wake.try_send(()) {
+                                return;
+                            }
+                        }
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            info!(
+                                "No zmq block notification from {} in {:?}, reconnecting",
+                                endpoint, silence_timeout
+                            );
+                            break;
+                        }
+                        Err(e) => {
+                            warn!("Lost zmq block notifications from {}: {}", endpoint, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to subscribe to zmq block notifications at {}: {}", endpoint, e),
+        }
+        thread::sleep(retry_interval);
+    }
 }
 
 impl BlockFetcher<bitcoin::Block> for bitcoincore_rpc::Client
@@ -38,11 +158,19 @@ where
 {
     type ErrorT = bitcoincore_rpc::Error;
 
-    fn new(url: &str) -> Result<Self, Self::ErrorT> {
-        Self::new(
-            url,
-            Auth::CookieFile("/home/sickguy/.bitcoin/regtest/regtest/.cookie".into()),
-        )
+    fn new(url: &str, auth: &DaemonAuth) -> Result<Self, Self::ErrorT> {
+        let auth = match auth {
+            DaemonAuth::None => Auth::None,
+            DaemonAuth::Cookie { path } => Auth::CookieFile(path.clone()),
+            DaemonAuth::UserPass { user, password } => {
+                Auth::UserPass(user.clone(), password.clone())
+            }
+        };
+
+        let client = Self::new(url, auth)?;
+        // wrong credentials only show up on the first call
+        RpcApi::get_best_block_hash(&client)?;
+        Ok(client)
     }
 
     fn fetch_blocktemplate(
@@ -82,4 +210,24 @@ where
             RpcApi::get_best_block_hash(self)?.to_byte_array(),
         ))
     }
+
+    fn get_network(&self) -> Result<bitcoin::Network, bitcoincore_rpc::Error> {
+        // only read the chain, the rest of the result differs between daemon versions
+        let info: serde_json::Value = self.call("getblockchaininfo", &[])?;
+
+        info["chain"]
+            .as_str()
+            .and_then(|chain| bitcoin::Network::from_core_arg(chain).ok())
+            .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)
+    }
+
+    fn long_poll(&self, longpollid: Option<&str>) -> Result<Option<String>, bitcoincore_rpc::Error> {
+        let mut request = json!({"rules": ["segwit"]});
+        if let Some(id) = longpollid {
+            request["longpollid"] = json!(id);
+        }
+
+        let template: Value = self.call("getblocktemplate", &[request])?;
+        Ok(template["longpollid"].as_str().map(String::from))
+    }
 }
@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
 
 use bitcoin::ScriptBuf;
 use crypto_bigint::U256;
@@ -8,9 +11,15 @@ use crate::{p2p::networking::{block::Block, share::CoinbaseEncodedP2P}, stratum:
 
 use super::{header::BlockHeader, job::JobBtc, job_fetcher::BlockFetcher};
 
+// how many recent jobs on the current main tip shares are still accepted for
+pub const MAX_JOB_HISTORY: usize = 16;
+// pool shares for a job replaced without clean_jobs are accepted this long after
+pub const STALE_JOB_GRACE: Duration = Duration::from_secs(30);
+
 pub struct JobManager<JobT> {
     job_count: u32,
-    jobs: HashMap<u32, JobT>,
+    // consecutive ids, oldest first
+    jobs: VecDeque<JobT>,
 }
 
 // job manager is responsible for generating and updating jobs, the only one that can mutate jobs
@@ -21,17 +30,17 @@ where
     pub fn new<Fetcher: BlockFetcher<bitcoin::Block>>(
         header_fetcher: &Fetcher,
     ) -> JobManager<JobBtc<bitcoin::Block, E>> {
-        let mut jobs = HashMap::with_capacity(16);
+        let mut jobs = VecDeque::with_capacity(MAX_JOB_HISTORY);
 
         // this is an invalid job, no outputs, a new one should be generated immediately
         match header_fetcher.fetch_blocktemplate(std::iter::empty(), CoinbaseEncodedP2P::default()) {
             Ok(res) => {
                 let id = 0;
-                let job = JobBtc::new(id, res);
+                let job = JobBtc::new(id, res, true);
 
                 info!("First job: {:#?}", job);
 
-                jobs.insert(id, job);
+                jobs.push_back(job);
             }
             Err(e) => panic!("Failed to generate 1st job: {}", e),
         }
@@ -50,19 +59,28 @@ where
         if fetched
             .block
             .get_header()
-            .equal(&self.jobs[&(self.job_count - 1)].block.get_header())
+            .equal(self.last_job().block.get_header())
         {
             return Ok(None);
         }
 
+        // shares of the previous main tip can't be blocks anymore
+        let clean_jobs =
+            fetched.block.get_header().get_prev() != self.last_job().block.get_header().get_prev();
+        if clean_jobs {
+            self.jobs.clear();
+        } else if self.jobs.len() >= MAX_JOB_HISTORY {
+            self.jobs.pop_front();
+        }
+
         let id = self.job_count;
-        let job = JobBtc::new(id, fetched);
+        let job = JobBtc::new(id, fetched, clean_jobs);
 
         self.job_count += 1;
 
-        self.jobs.insert(id, job);
+        self.jobs.push_back(job);
 
-        Ok(Some(self.jobs.get(&id).unwrap()))
+        Ok(Some(self.last_job()))
     }
 
     pub fn get_job_count(&self) -> u32 {
@@ -70,10 +88,10 @@ where
     }
 
     pub fn last_job(&self) -> &JobBtc<bitcoin::Block, E> {
-        &self.jobs[&(self.job_count - 1)]
+        self.jobs.back().unwrap()
     }
 
     pub fn get_jobs(&self) -> HashMap<u32, JobBtc<bitcoin::Block, E>> {
-        self.jobs.clone()
+        self.jobs.iter().map(|job| (job.id, job.clone())).collect()
     }
 }
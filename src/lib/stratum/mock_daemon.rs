@@ -0,0 +1,652 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hashes::Hash,
+    BlockHash, Network, ScriptBuf,
+};
+use bitcoincore_rpc::bitcoincore_rpc_json::GetBlockTemplateResult;
+use crypto_bigint::{Encoding, U256};
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::p2p::networking::{block::Block, pplns::MAX_SCORE, share::CoinbaseEncodedP2P};
+
+use super::{
+    job_fetcher::{BlockFetch, BlockFetcher, DaemonAuth},
+    zmq::{handshake, read_message, write_frame, HASHBLOCK_TOPIC},
+};
+
+const DEFAULT_TEMPLATE: &str = include_str!("../../../test_data/segwit_block_template.json");
+// how often a waiting long poll checks the chain
+const LONG_POLL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// an in memory chain standing in for bitcoind, templates are built from a fixture
+#[derive(Debug)]
+pub struct MockChain {
+    network: Network,
+    template: Value,
+    // index is the height
+    blocks: Vec<bitcoin::Block>,
+    submitted: Vec<bitcoin::Block>,
+}
+
+impl MockChain {
+    pub fn new(network: Network, template: Value) -> Self {
+        Self {
+            network,
+            template,
+            blocks: vec![bitcoin::blockdata::constants::genesis_block(network)],
+            submitted: Vec::new(),
+        }
+    }
+
+    pub fn regtest() -> Self {
+        Self::new(
+            Network::Regtest,
+            serde_json::from_str(DEFAULT_TEMPLATE).unwrap(),
+        )
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.blocks.len() - 1) as u32
+    }
+
+    pub fn tip_hash(&self) -> BlockHash {
+        self.blocks.last().unwrap().block_hash()
+    }
+
+    pub fn block(&self, hash: &BlockHash) -> Option<&bitcoin::Block> {
+        self.blocks.iter().find(|b| &b.block_hash() == hash)
+    }
+
+    // every block given to submitblock, accepted or not
+    pub fn submitted(&self) -> &[bitcoin::Block] {
+        &self.submitted
+    }
+
+    // the fixture on top of the current tip, the time moves with the height so templates differ
+    pub fn block_template(&self) -> Value {
+        let mut template = self.template.clone();
+        let curtime = template["curtime"].as_u64().unwrap_or(0) + self.height() as u64;
+
+        template["height"] = json!(self.height() + 1);
+        template["previousblockhash"] = json!(self.tip_hash().to_string());
+        template["curtime"] = json!(curtime);
+        template["longpollid"] = json!(self.longpollid());
+        template
+    }
+
+    // bitcoind also counts mempool updates, there is no mempool here
+    pub fn longpollid(&self) -> String {
+        self.tip_hash().to_string()
+    }
+
+    pub fn parsed_template(&self) -> GetBlockTemplateResult {
+        serde_json::from_value(self.block_template()).unwrap()
+    }
+
+    // extends the chain like a block from another miner would
+    pub fn advance_tip(&mut self) -> BlockHash {
+        let (block, _) = bitcoin::Block::from_block_template(
+            &self.parsed_template(),
+            [(ScriptBuf::new(), MAX_SCORE)].into_iter(),
+            CoinbaseEncodedP2P::default(),
+        );
+        let hash = block.block_hash();
+
+        self.blocks.push(block);
+        hash
+    }
+
+    // only the link to the tip is checked, not the work
+    pub fn submit(&mut self, block: bitcoin::Block) -> Result<(), &'static str> {
+        self.submitted.push(block.clone());
+
+        if self.block(&block.block_hash()).is_some() {
+            return Err("duplicate");
+        }
+
+        if block.header.prev_blockhash != self.tip_hash() {
+            return Err("bad-prevblk");
+        }
+
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    fn process_rpc(&mut self, method: &str, params: &Value) -> Result<Value, (i32, String)> {
+        match method {
+            "getblockchaininfo" => Ok(json!({
+                "chain": self.network.to_core_arg(),
+                "blocks": self.height(),
+                "headers": self.height(),
+                "bestblockhash": self.tip_hash().to_string(),
+            })),
+            "getbestblockhash" => Ok(json!(self.tip_hash().to_string())),
+            "getblocktemplate" => Ok(self.block_template()),
+            "getblock" => {
+                let hash: BlockHash = params[0]
+                    .as_str()
+                    .and_then(|h| h.parse().ok())
+                    .ok_or((-8, String::from("Invalid block hash")))?;
+
+                match self.block(&hash) {
+                    Some(block) => Ok(json!(hex::encode(serialize(block)))),
+                    None => Err((-5, String::from("Block not found"))),
+                }
+            }
+            "submitblock" => {
+                let block: bitcoin::Block = params[0]
+                    .as_str()
+                    .and_then(|h| hex::decode(h).ok())
+                    .and_then(|bytes| deserialize(&bytes).ok())
+                    .ok_or((-22, String::from("Block decode failed")))?;
+
+                // like bitcoind, rejections are a result, not an error
+                match self.submit(block) {
+                    Ok(()) => Ok(Value::Null),
+                    Err(reason) => Ok(json!(reason)),
+                }
+            }
+            _ => Err((-32601, String::from("Method not found"))),
+        }
+    }
+}
+
+// serves a mock chain over json rpc, for the parts that create their own bitcoincore_rpc client
+pub struct MockDaemon {
+    pub chain: Arc<Mutex<MockChain>>,
+    address: SocketAddr,
+}
+
+impl MockDaemon {
+    pub fn start(chain: MockChain) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let chain = Arc::new(Mutex::new(chain));
+
+        let chain_cp = chain.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(k) => k,
+                    Err(e) => {
+                        warn!("Mock daemon failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let chain = chain_cp.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve_connection(stream, &chain) {
+                        warn!("Mock daemon connection closed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { chain, address })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+// the rpc client keeps connections alive, so serve requests until it hangs up
+fn serve_connection(stream: TcpStream, chain: &Mutex<MockChain>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    while let Some(body) = read_http_request(&mut reader)? {
+        let response = match serde_json::from_slice::<Value>(&body) {
+            Ok(req) => {
+                let method = req["method"].as_str().unwrap_or_default();
+                if method == "getblocktemplate" {
+                    if let Some(id) = req["params"][0]["longpollid"].as_str() {
+                        wait_template_change(chain, id);
+                    }
+                }
+                let result = chain.lock().unwrap().process_rpc(method, &req["params"]);
+
+                match result {
+                    Ok(result) => json!({"result": result, "error": null, "id": req["id"]}),
+                    Err((code, message)) => json!({
+                        "result": null,
+                        "error": {"code": code, "message": message},
+                        "id": req["id"],
+                    }),
+                }
+            }
+            Err(e) => json!({
+                "result": null,
+                "error": {"code": -32700, "message": e.to_string()},
+                "id": null,
+            }),
+        };
+
+        let body = response.to_string();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// long polls hold until the chain moves on from the given template
+fn wait_template_change(chain: &Mutex<MockChain>, longpollid: &str) {
+    while chain.lock().unwrap().longpollid() == longpollid {
+        thread::sleep(LONG_POLL_CHECK_INTERVAL);
+    }
+}
+
+// none when the connection was closed
+fn read_http_request(reader: &mut BufReader<TcpStream>) -> io::Result<Option<Vec<u8>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line == "\r\n" {
+            break;
+        }
+
+        let lower = line.to_ascii_lowercase();
+        if let Some(len) = lower.strip_prefix("content-length:") {
+            content_length = len
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad content-length"))?;
+        }
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+// a connection and the topic it subscribed to
+type ZmqSubscription = (TcpStream, Vec<u8>);
+
+// stands in for bitcoind's zmq publishers, messages go to the subscribers of their topic
+pub struct MockZmqPublisher {
+    subscribers: Arc<Mutex<Vec<ZmqSubscription>>>,
+    address: SocketAddr,
+}
+
+impl MockZmqPublisher {
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        let subscribers_cp = subscribers.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let subscribed = stream.and_then(|mut stream| {
+                    handshake(&mut stream, "PUB")?;
+                    let subscription = read_message(&mut stream)?.concat();
+                    match subscription.split_first() {
+                        Some((1, topic)) => Ok((stream, topic.to_vec())),
+                        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected a subscription")),
+                    }
+                });
+
+                match subscribed {
+                    Ok(subscriber) => subscribers_cp.lock().unwrap().push(subscriber),
+                    Err(e) => warn!("Mock zmq publisher failed to accept subscriber: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            subscribers,
+            address,
+        })
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("tcp://{}", self.address)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    // the first part is the topic, subscribers that went away are dropped
+    pub fn publish(&self, parts: &[&[u8]]) {
+        self.subscribers.lock().unwrap().retain_mut(|(stream, topic)| {
+            if !parts[0].starts_with(topic) {
+                return true;
+            }
+            parts.iter().enumerate().all(|(i, part)| {
+                write_frame(stream, part, i + 1 < parts.len(), false).is_ok()
+            })
+        });
+    }
+
+    // bitcoind's hashblock notification, the hash in rpc byte order
+    pub fn publish_hashblock(&self, hash: &BlockHash, sequence: u32) {
+        let mut hash = hash.to_byte_array();
+        hash.reverse();
+        self.publish(&[HASHBLOCK_TOPIC, &hash, &sequence.to_le_bytes()]);
+    }
+}
+
+// a fetcher that talks to a mock chain in process, without any sockets
+#[derive(Debug, Clone)]
+pub struct MockFetcher {
+    pub chain: Arc<Mutex<MockChain>>,
+}
+
+impl MockFetcher {
+    pub fn with_chain(chain: Arc<Mutex<MockChain>>) -> Self {
+        Self { chain }
+    }
+}
+
+impl BlockFetcher<bitcoin::Block> for MockFetcher {
+    type ErrorT = bitcoincore_rpc::Error;
+
+    // there is no daemon to reach, every fetcher gets a fresh regtest chain
+    fn new(_url: &str, _auth: &DaemonAuth) -> Result<Self, Self::ErrorT> {
+        Ok(Self::with_chain(Arc::new(Mutex::new(MockChain::regtest()))))
+    }
+
+    fn fetch_blocktemplate(
+        &self,
+        vout: impl Iterator<Item = (ScriptBuf, u64)>,
+        cb_encoded: CoinbaseEncodedP2P,
+    ) -> Result<BlockFetch<bitcoin::Block>, Self::ErrorT> {
+        let template = self.chain.lock().unwrap().parsed_template();
+        let (block, tx_hashes) = bitcoin::Block::from_block_template(&template, vout, cb_encoded);
+
+        Ok(BlockFetch {
+            block,
+            tx_hashes,
+            height: template.height as u32,
+            reward: template.coinbase_value.to_sat(),
+        })
+    }
+
+    fn submit_block(&self, block: &bitcoin::Block) -> Result<(), bitcoincore_rpc::Error> {
+        self.chain
+            .lock()
+            .unwrap()
+            .submit(block.clone())
+            .map_err(|e| bitcoincore_rpc::Error::ReturnedError(e.to_string()))
+    }
+
+    fn fetch_block(&self, hash: &U256) -> Result<bitcoin::Block, bitcoincore_rpc::Error> {
+        let hash = BlockHash::from_byte_array(hash.to_be_bytes());
+
+        match self.chain.lock().unwrap().block(&hash) {
+            Some(block) => Ok(block.clone()),
+            None => Err(bitcoincore_rpc::Error::ReturnedError(String::from(
+                "Block not found",
+            ))),
+        }
+    }
+
+    fn get_best_blockhash(&self) -> Result<U256, bitcoincore_rpc::Error> {
+        Ok(U256::from_be_bytes(
+            self.chain.lock().unwrap().tip_hash().to_byte_array(),
+        ))
+    }
+
+    fn get_network(&self) -> Result<Network, bitcoincore_rpc::Error> {
+        Ok(self.chain.lock().unwrap().network)
+    }
+
+    fn long_poll(&self, longpollid: Option<&str>) -> Result<Option<String>, bitcoincore_rpc::Error> {
+        if let Some(id) = longpollid {
+            wait_template_change(&self.chain, id);
+        }
+        Ok(Some(self.chain.lock().unwrap().longpollid()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bitcoin::{hashes::Hash, Network, ScriptBuf};
+    use crypto_bigint::{Encoding, U256};
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        coins::bitcoin::Btc,
+        p2p::networking::{block::Block, protocol::ProtocolP2P, share::CoinbaseEncodedP2P},
+        sickrpc::RpcReqBody,
+        stratum::{
+            job::JobBtc,
+            job_fetcher::{long_poll_templates, subscribe_new_blocks, BlockFetcher, DaemonAuth},
+            job_manager::{JobManager, MAX_JOB_HISTORY},
+        },
+    };
+
+    use super::{MockChain, MockDaemon, MockFetcher, MockZmqPublisher};
+
+    #[test]
+    fn rpc_client_against_mock_daemon() {
+        let daemon = MockDaemon::start(MockChain::regtest()).unwrap();
+        let client = <bitcoincore_rpc::Client as BlockFetcher<bitcoin::Block>>::new(
+            &daemon.url(),
+            &DaemonAuth::None,
+        )
+        .unwrap();
+
+        assert_eq!(client.get_network().unwrap(), Network::Regtest);
+
+        let fetched = client
+            .fetch_blocktemplate(std::iter::empty(), CoinbaseEncodedP2P::default())
+            .unwrap();
+        assert_eq!(fetched.height, 1);
+        assert!(fetched.block.check_witness_commitment());
+
+        client.submit_block(&fetched.block).unwrap();
+        // the same block again no longer links to the tip
+        assert!(client.submit_block(&fetched.block).is_err());
+
+        let hash = U256::from_be_bytes(fetched.block.block_hash().to_byte_array());
+        assert_eq!(client.get_best_blockhash().unwrap(), hash);
+        assert_eq!(client.fetch_block(&hash).unwrap(), fetched.block);
+        assert_eq!(daemon.chain.lock().unwrap().submitted().len(), 2);
+
+        let next = client
+            .fetch_blocktemplate(std::iter::empty(), CoinbaseEncodedP2P::default())
+            .unwrap();
+        assert_eq!(next.height, 2);
+        assert_eq!(next.block.header.prev_blockhash, fetched.block.block_hash());
+    }
+
+    #[test]
+    fn new_tip_creates_new_job() {
+        let fetcher = MockFetcher::new("", &DaemonAuth::None).unwrap();
+        let mut job_manager: JobManager<JobBtc<bitcoin::Block, RpcReqBody>> =
+            JobManager::new(&fetcher);
+
+        // same template, nothing to do
+        let same = job_manager
+            .get_new_job(&fetcher, std::iter::empty(), CoinbaseEncodedP2P::default())
+            .unwrap();
+        assert!(same.is_none());
+
+        let tip = fetcher.chain.lock().unwrap().advance_tip();
+        let job = job_manager
+            .get_new_job(&fetcher, std::iter::empty(), CoinbaseEncodedP2P::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(job.block.get_header().prev_blockhash, tip);
+        assert_eq!(job.broadcast_message.1[8], true);
+        assert_eq!(job_manager.get_job_count(), 2);
+    }
+
+    #[test]
+    fn job_history_is_bounded() {
+        let fetcher = MockFetcher::new("", &DaemonAuth::None).unwrap();
+        let mut job_manager: JobManager<JobBtc<bitcoin::Block, RpcReqBody>> =
+            JobManager::new(&fetcher);
+
+        // a different payout is a different job on the same tip
+        for i in 1..=MAX_JOB_HISTORY as u64 + 4 {
+            let script = ScriptBuf::new_op_return(&[i as u8]);
+            let job = job_manager
+                .get_new_job(&fetcher, [(script, i)].into_iter(), CoinbaseEncodedP2P::default())
+                .unwrap()
+                .unwrap();
+            // miners can keep working on the older ones
+            assert_eq!(job.broadcast_message.1[8], false);
+        }
+
+        let jobs = job_manager.get_jobs();
+        assert_eq!(jobs.len(), MAX_JOB_HISTORY);
+        assert!(!jobs.contains_key(&4));
+        assert!(jobs.contains_key(&5));
+
+        // a new tip drops every job on the old one
+        fetcher.chain.lock().unwrap().advance_tip();
+        job_manager
+            .get_new_job(&fetcher, std::iter::empty(), CoinbaseEncodedP2P::default())
+            .unwrap()
+            .unwrap();
+
+        let jobs = job_manager.get_jobs();
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs.contains_key(&(MAX_JOB_HISTORY as u32 + 5)));
+    }
+
+    #[test]
+    fn long_poll_wakes_on_new_template() {
+        let daemon = MockDaemon::start(MockChain::regtest()).unwrap();
+        let client = <bitcoincore_rpc::Client as BlockFetcher<bitcoin::Block>>::new(
+            &daemon.url(),
+            &DaemonAuth::None,
+        )
+        .unwrap();
+
+        let tip = daemon.chain.lock().unwrap().tip_hash();
+        assert_eq!(client.long_poll(None).unwrap(), Some(tip.to_string()));
+
+        let (wake_tx, wake_rx) = flume::bounded(1);
+        let active = Arc::new(AtomicBool::new(false));
+        let active_cp = active.clone();
+        std::thread::spawn(move || {
+            long_poll_templates(&client, &wake_tx, &active_cp, Duration::from_secs(1))
+        });
+
+        // the first template doesn't wait
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(active.load(Ordering::Relaxed));
+        assert!(wake_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        daemon.chain.lock().unwrap().advance_tip();
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn zmq_hashblock_wakes() {
+        let publisher = MockZmqPublisher::start().unwrap();
+        let endpoint = publisher.endpoint();
+
+        let (wake_tx, wake_rx) = flume::bounded(1);
+        std::thread::spawn(move || {
+            let (retry, silence) = (Duration::from_millis(100), Duration::from_secs(60));
+            subscribe_new_blocks(&endpoint, &wake_tx, retry, silence)
+        });
+
+        let start = std::time::Instant::now();
+        while publisher.subscriber_count() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // other topics aren't sent to a hashblock subscriber
+        publisher.publish(&[b"hashtx", &[0; 32], &[0; 4]]);
+        assert!(wake_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let mut chain = MockChain::regtest();
+        publisher.publish_hashblock(&chain.advance_tip(), 0);
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn silent_zmq_publishers_are_reconnected() {
+        let publisher = MockZmqPublisher::start().unwrap();
+        let endpoint = publisher.endpoint();
+
+        let (wake_tx, wake_rx) = flume::bounded(1);
+        std::thread::spawn(move || {
+            let (retry, silence) = (Duration::from_millis(10), Duration::from_millis(200));
+            subscribe_new_blocks(&endpoint, &wake_tx, retry, silence)
+        });
+
+        // a half open connection is just as quiet, the subscriber gives up on it and subscribes again
+        let start = std::time::Instant::now();
+        while publisher.subscriber_count() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // and still hears about blocks, unless one is published right as it reconnects
+        let mut chain = MockChain::regtest();
+        let hash = chain.advance_tip();
+        for sequence in 0.. {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            publisher.publish_hashblock(&hash, sequence);
+            if wake_rx.recv_timeout(Duration::from_millis(100)).is_ok() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn new_pool_genesis_from_mock_daemon() {
+        let daemon = MockDaemon::start(MockChain::regtest()).unwrap();
+        let data_dir = std::env::temp_dir()
+            .join(format!("sickpool-new-pool-{}", std::process::id()))
+            .into_boxed_path();
+
+        let config = ProtocolP2P::<Btc>::get_new_pool_config(
+            data_dir,
+            String::from("test"),
+            daemon.url(),
+            DaemonAuth::None,
+            Network::Regtest,
+            1,
+            1000,
+        );
+
+        let genesis = &config.consensus.genesis_block;
+        assert_eq!(config.consensus.network, Network::Regtest);
+        assert_eq!(
+            genesis.header.prev_blockhash,
+            daemon.chain.lock().unwrap().tip_hash()
+        );
+        let _ = std::fs::remove_dir_all(&config.data_dir);
+    }
+}
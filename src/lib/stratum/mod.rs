@@ -8,4 +8,8 @@ pub mod protocol;
 pub mod stratum_v1;
 pub mod common;
 pub mod handler;
-pub mod server;
\ No newline at end of file
+pub mod server;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_daemon;
+pub mod vardiff;
+pub mod zmq;
@@ -1,18 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-
-
+use crate::coins::coin::Coin;
 use crate::config::ProtocolServerConfig;
+use log::warn;
 use crate::p2p::networking::protocol::ProtocolP2P;
 use crate::protocol::Protocol;
 
 use crate::{server::Server};
 
+use super::job_fetcher::{
+    long_poll_templates, subscribe_new_blocks, BlockFetcher, ZMQ_SILENCE_TIMEOUT,
+};
 use super::protocol::StratumProtocol;
 use super::{config::StratumConfig};
 
+// while long polling, interval polling only catches what the long poll might have missed
+const LONG_POLL_JOB_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct StratumServer<T: StratumProtocol> {
     server: Server<T>,
 }
@@ -22,17 +29,50 @@ where
     T: StratumProtocol + Send + Sync + 'static + Protocol<Request = Vec<u8>, Response = Vec<u8>>,
 {
     pub fn new(conf: ProtocolServerConfig<StratumConfig>, p2p: Arc<ProtocolP2P<T::Coin>>) -> Self {
-        let job_poll_interval = conf.protocol_config.job_poll_interval_ms;
+        let job_poll_interval = Duration::from_millis(conf.protocol_config.job_poll_interval_ms);
+        let long_poll_daemon = <<T::Coin as Coin>::Fetcher as BlockFetcher<_>>::new(
+            &conf.protocol_config.rpc_url,
+            &conf.protocol_config.rpc_auth,
+        );
+
+        // new shares change the coinbase, new templates the whole block, both are fetched right away
+        let (wake_tx, wake_rx) = flume::bounded(1);
+        p2p.add_tip_listener(wake_tx.clone());
+
+        let long_polling = Arc::new(AtomicBool::new(false));
+        match long_poll_daemon {
+            Ok(daemon) => {
+                let (wake_tx, long_polling) = (wake_tx.clone(), long_polling.clone());
+                thread::spawn(move || {
+                    long_poll_templates(&daemon, &wake_tx, &long_polling, job_poll_interval)
+                });
+            }
+            Err(e) => warn!("Failed to connect to daemon for long polling: {}", e),
+        }
+
+        if let Some(endpoint) = conf.protocol_config.zmq_hashblock.clone() {
+            let wake_tx = wake_tx.clone();
+            thread::spawn(move || {
+                subscribe_new_blocks(&endpoint, &wake_tx, job_poll_interval, ZMQ_SILENCE_TIMEOUT)
+            });
+        }
+
         let protocol = Arc::new(T::new((conf.protocol_config, p2p)));
 
-        let job_poll_interval = Duration::from_millis(job_poll_interval);
         let protocol_poll_cp = protocol.clone();
         thread::spawn(move || {
+            // keeps the channel open when there is no one else to wake us
+            let _wake_tx = wake_tx;
             loop {
                 protocol_poll_cp.fetch_new_job();
                 // info!("Polling job...");
-                
-                thread::sleep(job_poll_interval);
+
+                let interval = if long_polling.load(Ordering::Relaxed) {
+                    LONG_POLL_JOB_INTERVAL
+                } else {
+                    job_poll_interval
+                };
+                let _ = wake_rx.recv_timeout(interval);
             }
         });
 
@@ -8,6 +8,7 @@ use serde_hex::*;
 use serde_tuple::Deserialize_tuple;
 
 use crate::coins::coin::Coin;
+use crate::framing::LineFraming;
 use crate::p2p::networking::protocol::SubmittingContext;
 use crate::p2p::networking::share::CoinbaseEncodedP2P;
 use crate::{
@@ -30,7 +31,7 @@ use serde_json::{json, Value};
 
 use crate::{
     p2p::networking::{
-        block::Block, difficulty::get_target_from_diff_units, hard_config::PPLNS_SHARE_UNITS,
+        block::Block, hard_config::PPLNS_SHARE_UNITS,
         protocol::ProtocolP2P, stratum_handler::CompleteStratumHandler,
     },
     protocol::{JsonRpcProtocol, Protocol},
@@ -46,8 +47,9 @@ use super::{
     header::BlockHeader,
     job::JobBtc,
     job_fetcher::BlockFetcher,
-    job_manager::JobManager,
+    job_manager::{JobManager, STALE_JOB_GRACE},
     protocol::StratumProtocol,
+    vardiff::VardiffConfig,
 };
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -58,14 +60,40 @@ pub enum StratumRequestsBtc {
     Subscribe,
     #[serde(rename="mining.authorize")]
     Authorize(AuthorizeReqParams),
+    #[serde(rename="mining.configure")]
+    Configure(ConfigureReqParams),
+    #[serde(rename="mining.suggest_difficulty")]
+    SuggestDifficulty(SuggestDifficultyReqParams),
 }
 
+// the version bits miners may roll (BIP320)
+pub const VERSION_ROLLING_MASK: u32 = 0x1fffe000;
+
 #[derive(Serialize, Deserialize_tuple, PartialEq, Debug)]
 pub struct AuthorizeReqParams {
     pub username: String,
     pub password: String,
 }
 
+#[derive(Serialize, Deserialize_tuple, PartialEq, Debug)]
+pub struct SuggestDifficultyReqParams {
+    pub difficulty: f64,
+}
+
+// miners put "d=<difficulty>" in the password, possibly among other comma separated options
+pub fn parse_difficulty_hint(password: &str) -> Option<f64> {
+    password
+        .split(',')
+        .find_map(|option| option.trim().strip_prefix("d="))
+        .and_then(|diff| diff.parse().ok())
+}
+
+// miners can only pick a starting difficulty within the vardiff bounds
+pub fn diff_units_from_hint(difficulty: f64, conf: &VardiffConfig) -> u64 {
+    ((difficulty * PPLNS_SHARE_UNITS as f64) as u64)
+        .clamp(conf.min_diff_units, conf.max_diff_units.max(conf.min_diff_units))
+}
+
 #[derive(Serialize, Deserialize_tuple, PartialEq, Debug)]
 pub struct SubmitReqParams {
     pub worker_name: String,
@@ -77,6 +105,34 @@ pub struct SubmitReqParams {
     pub time: u32,
     #[serde(with = "SerHex::<Strict>")]
     pub nonce: u32,
+    // only sent by clients that negotiated version rolling
+    #[serde(default, with = "opt_hex_u32")]
+    pub version_bits: Option<u32>,
+}
+
+// serde-hex's option support can't read owned strings
+mod opt_hex_u32 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&hex::encode(value.to_be_bytes())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| u32::from_str_radix(&value, 16).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+#[derive(Serialize, Deserialize_tuple, PartialEq, Debug)]
+pub struct ConfigureReqParams {
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    pub options: serde_json::Map<String, Value>,
 }
 
 #[repr(u32)]
@@ -88,6 +144,7 @@ pub enum StratumV1ErrorCodes {
     LowDifficultyShare = 23,
     UnauthorizedWorker = 24,
     NotSubscribed = 25,
+    StaleJob = 26,
 }
 
 pub trait Discriminant {
@@ -109,6 +166,7 @@ impl fmt::Display for StratumV1ErrorCodes {
             StratumV1ErrorCodes::LowDifficultyShare => write!(f, "Low difficulty share"),
             StratumV1ErrorCodes::UnauthorizedWorker => write!(f, "Unauthorized worker"),
             StratumV1ErrorCodes::NotSubscribed => write!(f, "Client not subscribed"),
+            StratumV1ErrorCodes::StaleJob => write!(f, "Stale job"),
         }
     }
 }
@@ -118,11 +176,22 @@ pub struct StratumV1 {
     client_count: AtomicU32,
     config: StratumConfig,
     pub handler: CompleteStratumHandler<Btc>,
-    pub subscribed_clients: Mutex<Slab<Notifier>>,
+    pub subscribed_clients: Mutex<Slab<Arc<Mutex<StratumClient>>>>,
     pub daemon_cli: <Btc as Coin>::Fetcher,
 }
 
 impl StratumV1 {
+    fn network(&self) -> bitcoin::Network {
+        self.handler.p2p.conf.consensus.network
+    }
+
+    fn set_difficulty_message(diff_units: u64) -> RpcReqBody {
+        (
+            "mining.set_difficulty".into(),
+            json!([diff_units as f64 / PPLNS_SHARE_UNITS as f64]),
+        )
+    }
+
     pub fn process_stratum_request(
         &self,
         req: StratumRequestsBtc,
@@ -140,7 +209,7 @@ impl StratumV1 {
                     .subscribed_clients
                     .lock()
                     .unwrap()
-                    .insert(lock.notifier.clone());
+                    .insert(ctx.clone());
 
                 lock.subscription_key = Some(key);
                 Ok((
@@ -156,9 +225,33 @@ impl StratumV1 {
                     Vec::new(),
                 ))
             }
+            StratumRequestsBtc::Configure(params) => {
+                Ok((self.process_configure(params, &mut ctx.lock().unwrap()), Vec::new()))
+            }
+            StratumRequestsBtc::SuggestDifficulty(params) => {
+                let diff = diff_units_from_hint(params.difficulty, &self.config.vardiff);
+                let mut lock = ctx.lock().unwrap();
+                lock.suggested_diff_units = Some(diff);
+
+                // before authorizing it's only kept for the workers to come
+                if lock.authorized_workers.is_empty() {
+                    return Ok((Value::Bool(true), Vec::new()));
+                }
+                lock.set_diff_units(diff, &Btc::DIFF1);
+                std::mem::drop(lock);
+
+                let notifs = Vec::from([
+                    Self::set_difficulty_message(diff),
+                    self.job_manager.read().unwrap().last_job().broadcast_message.clone(),
+                ]);
+                Ok((Value::Bool(true), notifs))
+            }
+            StratumRequestsBtc::Authorize(_) if !self.handler.p2p.is_synced() => Err(
+                StratumV1ErrorCodes::Other(String::from("Pool is syncing, try again later")),
+            ),
             StratumRequestsBtc::Authorize(params) => {
                 // TODO: get address
-                let _pk = match MyBtcAddr::from_string(&params.username) {
+                let _pk = match MyBtcAddr::from_string(&params.username, self.network()) {
                     Ok(k) => k,
                     Err(_) => {
                         return Err(StratumV1ErrorCodes::Other(String::from(
@@ -166,23 +259,33 @@ impl StratumV1 {
                         )));
                     }
                 };
-                ctx.lock()
-                    .unwrap()
-                    .authorized_workers
-                    .insert(params.username.clone(), params.username);
+                // the worker's own hint wins over the connection's suggestion
+                let hint = parse_difficulty_hint(&params.password)
+                    .map(|d| diff_units_from_hint(d, &self.config.vardiff));
+                let diff = {
+                    let mut lock = ctx.lock().unwrap();
+                    // set_difficulty is per connection, so is the worker it applies to
+                    if !lock.authorized_workers.is_empty()
+                        && !lock.authorized_workers.contains_key(&params.username)
+                    {
+                        return Err(StratumV1ErrorCodes::Other(String::from(
+                            "Only one worker per connection",
+                        )));
+                    }
+                    lock.authorized_workers
+                        .insert(params.username.clone(), params.username);
+                    hint.or(lock.suggested_diff_units)
+                        .unwrap_or(self.config.default_diff_units)
+                };
 
                 let jobs = self.job_manager.read().unwrap();
                 let job = jobs.last_job();
 
-                let diff = self.config.default_diff_units;
                 let notifs = Vec::from([
-                    (
-                        "mining.set_difficulty".into(),
-                        json!([diff as f64 / PPLNS_SHARE_UNITS as f64]),
-                    ),
+                    Self::set_difficulty_message(diff),
                     job.broadcast_message.clone(),
                 ]);
-                ctx.lock().unwrap().target = get_target_from_diff_units(diff, &Btc::DIFF1);
+                ctx.lock().unwrap().set_diff_units(diff, &Btc::DIFF1);
 
                 Ok((Value::Bool(true), notifs))
             }
@@ -193,19 +296,70 @@ impl StratumV1 {
         res
     }
 
+    // BIP310, only version rolling is supported
+    fn process_configure(&self, params: ConfigureReqParams, client: &mut StratumClient) -> Value {
+        let mut res = serde_json::Map::new();
+
+        for extension in params.extensions {
+            if extension != "version-rolling" {
+                res.insert(extension, Value::Bool(false));
+                continue;
+            }
+
+            let requested_mask = match params.options.get("version-rolling.mask") {
+                Some(mask) => mask
+                    .as_str()
+                    .and_then(|mask| u32::from_str_radix(mask, 16).ok())
+                    .unwrap_or(0),
+                None => u32::MAX,
+            };
+            let min_bit_count = params
+                .options
+                .get("version-rolling.min-bit-count")
+                .and_then(Value::as_u64)
+                .unwrap_or(0);
+
+            let mask = requested_mask & VERSION_ROLLING_MASK;
+            if (mask.count_ones() as u64) < min_bit_count {
+                client.version_mask = 0;
+                res.insert(extension, Value::Bool(false));
+                continue;
+            }
+
+            client.version_mask = mask;
+            res.insert(extension, Value::Bool(true));
+            res.insert(
+                String::from("version-rolling.mask"),
+                Value::String(hex::encode(mask.to_be_bytes())),
+            );
+        }
+        Value::Object(res)
+    }
+
     fn process_submit(
         &self,
         params: SubmitReqParams,
         ctx: Arc<Mutex<StratumClient>>,
         ptx: &mut StratumProcessingContext<<Btc as Coin>::BlockT, RpcReqBody>,
     ) -> Result<(Value, Vec<RpcReqBody>), StratumV1ErrorCodes> {
-        if !ptx
-            .jobs
-            .contains_key(&(self.job_manager.read().unwrap().get_job_count() - 1))
         {
-            ptx.jobs = self.job_manager.read().unwrap().get_jobs()
+            let jobs = self.job_manager.read().unwrap();
+            if !ptx.jobs.contains_key(&(jobs.get_job_count() - 1)) {
+                ptx.jobs = jobs.get_jobs()
+            }
+
+            // only evicted jobs are stale, others were never sent
+            if params.job_id >= jobs.get_job_count() {
+                return Err(StratumV1ErrorCodes::JobNotFound);
+            }
         }
 
+        // miners only finish their current work on a job replaced without clean_jobs
+        let expired = ptx
+            .jobs
+            .get(&(params.job_id + 1))
+            .is_some_and(|next| next.created.elapsed() > STALE_JOB_GRACE);
+
         let mut job = ptx.jobs.get_mut(&params.job_id);
         let mut lock = ctx.lock().unwrap();
         let address = match lock.authorized_workers.get(&params.worker_name) {
@@ -213,27 +367,44 @@ impl StratumV1 {
             None => return Err(StratumV1ErrorCodes::UnauthorizedWorker),
         };
 
-        let res = process_share(&mut job, (params, lock.extra_nonce), &mut *lock);
+        let res = process_share(
+            &mut job,
+            (params, lock.extra_nonce, lock.version_mask),
+            &mut *lock,
+        );
+        // a block is still a block
+        let res = match res {
+            ShareResult::Valid(_) if expired => ShareResult::Stale(),
+            res => res,
+        };
+        let retarget = match res {
+            ShareResult::Valid(_) | ShareResult::Block(_) => {
+                lock.vardiff.add_share();
+                lock.retarget(&self.config.vardiff, &Btc::DIFF1, Instant::now())
+            }
+            _ => None,
+        };
         std::mem::drop(lock);
 
         match res {
             ShareResult::Block(diff) => {
                 info!("Found block! {}", diff);
                 let job = job.unwrap();
+                // still a valid share, the round closes on every node either way
                 if let Err(e) = self.daemon_cli.submit_block(&job.block) {
                     error!("Failed to submit block: {}", e);
                 }
 
                 self.handler.on_valid_share(
                     ctx.clone(),
-                    &MyBtcAddr::from_string(&address).unwrap(),
+                    &MyBtcAddr::from_string(&address, self.network()).unwrap(),
                     &job.block,
                     diff,
                 )
             }
             ShareResult::Valid(diff) => self.handler.on_valid_share(
                 ctx.clone(),
-                &MyBtcAddr::from_string(&address).unwrap(),
+                &MyBtcAddr::from_string(&address, self.network()).unwrap(),
                 &job.unwrap().block,
                 diff,
             ),
@@ -241,8 +412,17 @@ impl StratumV1 {
         };
 
         let res: Result<Value, StratumV1ErrorCodes> = res.into();
+        // the new difficulty only applies to jobs sent after it
+        let notifs = match retarget {
+            Some(diff) => Vec::from([
+                Self::set_difficulty_message(diff),
+                self.job_manager.read().unwrap().last_job().broadcast_message.clone(),
+            ]),
+            None => Vec::new(),
+        };
+
         match res {
-            Ok(k) => Ok((k, Vec::new())),
+            Ok(k) => Ok((k, notifs)),
             Err(e) => Err(e),
         }
     }
@@ -261,6 +441,11 @@ impl StratumProtocol for StratumV1 {
     type Coin = Btc;
 
     fn fetch_new_job(&self) {
+        // jobs built on a share chain that's still syncing would be stale
+        if !self.handler.p2p.is_synced() {
+            return;
+        }
+
         let mut lock = self.job_manager.write().unwrap();
         let res = lock.get_new_job(
             &self.daemon_cli,
@@ -284,11 +469,27 @@ impl StratumProtocol for StratumV1 {
 
         if let Ok(job) = res {
             if let Some(job) = job {
-                let lock = self.subscribed_clients.lock().unwrap();
-                info!("New job! broadcasting to {} clients", lock.len(),);
-
-                for (_token, notifier) in &*lock {
-                    JsonRpcProtocol::<Self>::notify(job.broadcast_message.clone(), notifier);
+                // subscribing locks the client before the slab, so don't hold both here
+                let clients: Vec<Arc<Mutex<StratumClient>>> = self
+                    .subscribed_clients
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(_token, client)| client.clone())
+                    .collect();
+                info!("New job! broadcasting to {} clients", clients.len(),);
+
+                let now = Instant::now();
+                for client in clients {
+                    let mut client = client.lock().unwrap();
+                    // clients that stopped submitting are only retargeted here
+                    if let Some(diff) = client.retarget(&self.config.vardiff, &Btc::DIFF1, now) {
+                        JsonRpcProtocol::<Self>::notify(
+                            Self::set_difficulty_message(diff),
+                            &client.notifier,
+                        );
+                    }
+                    JsonRpcProtocol::<Self>::notify(job.broadcast_message.clone(), &client.notifier);
                 }
                 // the received block is the one in the last job with the found params
                 self.handler.on_new_block(
@@ -300,6 +501,7 @@ impl StratumProtocol for StratumV1 {
                             .as_raw_hash()
                             .to_byte_array(),
                     ),
+                    &job.block.header.get_target(),
                 );
             }
         }
@@ -322,7 +524,7 @@ impl Into<Result<Value, StratumV1ErrorCodes>> for ShareResult {
     fn into(self) -> Result<Value, StratumV1ErrorCodes> {
         match self {
             ShareResult::Valid(_) | ShareResult::Block(_) => Ok(Value::Bool(true)),
-            ShareResult::Stale() => Err(StratumV1ErrorCodes::JobNotFound),
+            ShareResult::Stale() => Err(StratumV1ErrorCodes::StaleJob),
             ShareResult::Invalid() => Err(StratumV1ErrorCodes::LowDifficultyShare),
             ShareResult::Duplicate() => Err(StratumV1ErrorCodes::DuplicateShare),
         }
@@ -352,14 +554,16 @@ impl Protocol for StratumV1 {
     type Config = (StratumConfig, Arc<ProtocolP2P<Btc>>);
     type ClientContext = StratumClient;
     type ProcessingContext = StratumProcessingContext<<Btc as Coin>::BlockT, RpcReqBody>;
+    type Framing = LineFraming;
 
     fn new(conf: Self::Config) -> Self {
         // let p = .clone();
         let (stratum_conf, p2p) = conf;
         let daemon_cli = <<Btc as Coin>::Fetcher as BlockFetcher<bitcoin::Block>>::new(
             stratum_conf.rpc_url.as_ref(),
+            &stratum_conf.rpc_auth,
         )
-        .unwrap();
+        .expect("Failed to connect to the daemon");
 
         StratumV1 {
             job_manager: RwLock::new(JobManager::new(&daemon_cli)),
@@ -411,6 +615,26 @@ impl Protocol for StratumV1 {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{p2p::networking::hard_config::PPLNS_SHARE_UNITS, stratum::vardiff::VardiffConfig};
+
+    use super::diff_units_from_hint;
+
+    #[test]
+    fn difficulty_hints_are_clamped() {
+        let conf = VardiffConfig {
+            min_diff_units: PPLNS_SHARE_UNITS,
+            max_diff_units: 100 * PPLNS_SHARE_UNITS,
+            ..Default::default()
+        };
+
+        assert_eq!(diff_units_from_hint(8.0, &conf), 8 * PPLNS_SHARE_UNITS);
+        assert_eq!(diff_units_from_hint(0.001, &conf), conf.min_diff_units);
+        assert_eq!(diff_units_from_hint(1e9, &conf), conf.max_diff_units);
+    }
+}
+
 // demo \n
 /*
 {"id": 1, "method": "mining.subscribe", "params": []}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::p2p::networking::hard_config::PPLNS_SHARE_UNITS;
+
+// how far a single retarget can move a client's difficulty
+const MAX_VARDIFF_FACTOR: u128 = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VardiffConfig {
+    // the time between shares every client should settle on
+    pub target_share_interval_ms: u64,
+    // how often a client's share rate is checked
+    pub retarget_interval_ms: u64,
+    pub min_diff_units: u64,
+    pub max_diff_units: u64,
+}
+
+impl Default for VardiffConfig {
+    fn default() -> Self {
+        Self {
+            target_share_interval_ms: Duration::from_secs(10).as_millis() as u64,
+            retarget_interval_ms: Duration::from_secs(60).as_millis() as u64,
+            min_diff_units: PPLNS_SHARE_UNITS / 1000,
+            max_diff_units: PPLNS_SHARE_UNITS * 1_000_000_000,
+        }
+    }
+}
+
+// the share rate of a single client
+#[derive(Debug)]
+pub struct Vardiff {
+    pub diff_units: u64,
+    window_start: Instant,
+    window_shares: u64,
+}
+
+impl Vardiff {
+    pub fn new(diff_units: u64, now: Instant) -> Self {
+        Self {
+            diff_units,
+            window_start: now,
+            window_shares: 0,
+        }
+    }
+
+    pub fn add_share(&mut self) {
+        self.window_shares += 1;
+    }
+
+    // the difficulty for the next window once the retarget interval passed, it might be unchanged
+    pub fn retarget(&mut self, conf: &VardiffConfig, now: Instant) -> Option<u64> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < Duration::from_millis(conf.retarget_interval_ms) {
+            return None;
+        }
+
+        let current = self.diff_units as u128;
+        let wanted = current * self.window_shares as u128 * conf.target_share_interval_ms as u128
+            / elapsed.as_millis().max(1);
+        let wanted = wanted
+            .max(current / MAX_VARDIFF_FACTOR)
+            .min(current * MAX_VARDIFF_FACTOR)
+            .max(conf.min_diff_units as u128)
+            .min(conf.max_diff_units as u128)
+            .max(1) as u64;
+
+        self.window_start = now;
+        self.window_shares = 0;
+
+        // small corrections aren't worth a new job
+        if wanted.abs_diff(self.diff_units) * 10 >= self.diff_units {
+            self.diff_units = wanted;
+        }
+        Some(self.diff_units)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Vardiff, VardiffConfig};
+
+    fn config() -> VardiffConfig {
+        VardiffConfig {
+            target_share_interval_ms: 10_000,
+            retarget_interval_ms: 60_000,
+            min_diff_units: 100,
+            max_diff_units: 100_000,
+        }
+    }
+
+    #[test]
+    fn follows_share_rate_within_bounds() {
+        let conf = config();
+        let start = Instant::now();
+        let mut vardiff = Vardiff::new(1000, start);
+
+        // 12 shares in a minute is twice the wanted rate
+        for _ in 0..12 {
+            vardiff.add_share();
+        }
+        assert_eq!(vardiff.retarget(&conf, start + Duration::from_secs(30)), None);
+        assert_eq!(vardiff.retarget(&conf, start + Duration::from_secs(60)), Some(2000));
+
+        // a flood only moves it by the max factor
+        for _ in 0..1000 {
+            vardiff.add_share();
+        }
+        assert_eq!(vardiff.retarget(&conf, start + Duration::from_secs(120)), Some(8000));
+
+        // idle clients go down to the minimum
+        let mut now = start + Duration::from_secs(120);
+        for _ in 0..10 {
+            now += Duration::from_secs(60);
+            vardiff.retarget(&conf, now);
+        }
+        assert_eq!(vardiff.diff_units, conf.min_diff_units);
+    }
+
+    #[test]
+    fn ignores_small_corrections() {
+        let conf = config();
+        let start = Instant::now();
+        let mut vardiff = Vardiff::new(1000, start);
+
+        // 6.5 shares per minute would be 1083
+        for _ in 0..13 {
+            vardiff.add_share();
+        }
+        assert_eq!(vardiff.retarget(&conf, start + Duration::from_secs(120)), Some(1000));
+    }
+}
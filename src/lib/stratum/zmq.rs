@@ -0,0 +1,180 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+// just enough of zmtp 3.0 to talk to bitcoind's zmq publishers, without libzmq
+
+pub const HASHBLOCK_TOPIC: &[u8] = b"hashblock";
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+// 3.0 peers subscribe with a message, later versions still accept it
+const GREETING_VERSION: [u8; 2] = [3, 0];
+const SUBSCRIBE: u8 = 0x01;
+
+pub struct Frame {
+    pub body: Vec<u8>,
+    pub more: bool,
+    pub command: bool,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn write_frame(stream: &mut impl Write, body: &[u8], more: bool, command: bool) -> io::Result<()> {
+    let mut flags = 0;
+    if more {
+        flags |= FLAG_MORE;
+    }
+    if command {
+        flags |= FLAG_COMMAND;
+    }
+
+    let mut frame = Vec::with_capacity(body.len() + 9);
+    if body.len() > u8::MAX as usize {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+    stream.write_all(&frame)
+}
+
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Frame> {
+    let mut flags = [0u8; 1];
+    stream.read_exact(&mut flags)?;
+    let flags = flags[0];
+
+    let len = if flags & FLAG_LONG != 0 {
+        let mut len = [0u8; 8];
+        stream.read_exact(&mut len)?;
+        u64::from_be_bytes(len) as usize
+    } else {
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len)?;
+        len[0] as usize
+    };
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok(Frame {
+        body,
+        more: flags & FLAG_MORE != 0,
+        command: flags & FLAG_COMMAND != 0,
+    })
+}
+
+// the null mechanism, no security, both sides only tell their socket type
+pub fn handshake(stream: &mut TcpStream, socket_type: &str) -> io::Result<()> {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10..12].copy_from_slice(&GREETING_VERSION);
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 {
+        return Err(invalid("not a zmtp 3 peer"));
+    }
+    if &peer[12..16] != b"NULL" || peer[16..32].iter().any(|b| *b != 0) {
+        return Err(invalid("unsupported zmq security mechanism"));
+    }
+
+    let mut ready = Vec::new();
+    ready.push(5);
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    write_frame(stream, &ready, false, true)?;
+
+    let peer_ready = read_frame(stream)?;
+    if !peer_ready.command || !peer_ready.body.starts_with(b"\x05READY") {
+        return Err(invalid("expected zmq ready command"));
+    }
+    Ok(())
+}
+
+// the frames of every message until the last one
+pub fn read_message(stream: &mut impl Read) -> io::Result<Vec<Vec<u8>>> {
+    let mut parts = Vec::new();
+    loop {
+        let frame = read_frame(stream)?;
+        // heartbeats and such, nothing a subscriber has to answer
+        if frame.command {
+            continue;
+        }
+
+        parts.push(frame.body);
+        if !frame.more {
+            return Ok(parts);
+        }
+    }
+}
+
+pub struct ZmqSubscriber {
+    stream: TcpStream,
+}
+
+impl ZmqSubscriber {
+    // the endpoint as given to bitcoind, like tcp://127.0.0.1:28332.
+    // reads fail after the timeout, a half open connection never says so by itself
+    pub fn connect(endpoint: &str, topic: &[u8], read_timeout: Duration) -> io::Result<Self> {
+        let address = endpoint.strip_prefix("tcp://").ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only tcp zmq endpoints are supported",
+        ))?;
+
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        handshake(&mut stream, "SUB")?;
+
+        let mut subscribe = vec![SUBSCRIBE];
+        subscribe.extend_from_slice(topic);
+        write_frame(&mut stream, &subscribe, false, false)?;
+
+        Ok(Self { stream })
+    }
+
+    // bitcoind sends the topic, the body and a sequence number
+    pub fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        read_message(&mut self.stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame};
+
+    #[test]
+    fn frames_roundtrip() {
+        let long = vec![7u8; 300];
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hashblock", true, false).unwrap();
+        write_frame(&mut buf, &long, false, false).unwrap();
+
+        assert_eq!(&buf[..2], &[0x01, 9]);
+        assert_eq!(buf[11], 0x02);
+
+        let mut reader = buf.as_slice();
+        let first = read_frame(&mut reader).unwrap();
+        assert_eq!(first.body, b"hashblock");
+        assert!(first.more);
+
+        let second = read_frame(&mut reader).unwrap();
+        assert_eq!(second.body, long);
+        assert!(!second.more);
+        assert!(reader.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 #![deny(unsafe_code)]
 mod currency;
 
+use bitcoin::Network;
 use clap::{arg, command, ArgAction, Args, Parser, Subcommand};
 
 use log::info;
@@ -30,6 +31,8 @@ use std::sync::Arc;
 extern crate sickpool2lib;
 
 use sickpool2lib::stratum::config::StratumConfig;
+use sickpool2lib::stratum::job_fetcher::{BlockFetcher, DaemonAuth};
+use sickpool2lib::stratum::vardiff::VardiffConfig;
 
 // type StratumV1Json = JsonRpcProtocol<StratumV1<bitcoincore_rpc::Client>, StratumV1ErrorCodes>;
 fn read_config<T: serde::de::DeserializeOwned + serde::Serialize>(
@@ -58,6 +61,28 @@ fn read_config<T: serde::de::DeserializeOwned + serde::Serialize>(
     }
 }
 
+// fail early with a readable error rather than a panic inside the servers
+fn check_daemon(url: &str, auth: &DaemonAuth, network: Network) -> Result<(), String> {
+    let daemon = match <<Btc as Coin>::Fetcher as BlockFetcher<bitcoin::Block>>::new(url, auth) {
+        Ok(k) => k,
+        Err(e) => {
+            return Err(format!(
+                "Failed to connect to the daemon at {}: {}, check rpc_url and rpc_auth in the config",
+                url, e
+            ))
+        }
+    };
+
+    match daemon.get_network() {
+        Ok(k) if k == network => Ok(()),
+        Ok(k) => Err(format!(
+            "The daemon at {} is running on {}, but the pool is configured for {}",
+            url, k, network
+        )),
+        Err(e) => Err(format!("Failed to get the daemon's chain at {}: {}", url, e)),
+    }
+}
+
 #[derive(Args, Clone, Debug)]
 struct CreatePoolParams {
     #[clap(long)]
@@ -84,6 +109,9 @@ struct Cli {
     datadir: Option<PathBuf>,
     #[clap(long, short, action=ArgAction::SetFalse)]
     list_pools: bool,
+    /// The bitcoin network to run on (bitcoin, testnet, signet, regtest), defaults to the p2p config's
+    #[arg(long)]
+    network: Option<Network>,
     // create a pool under this one with the following params:
     #[command(subcommand)]
     command: Option<SubCommands>,
@@ -105,20 +133,61 @@ fn main() -> Result<(), String> {
     let stratum_config: ProtocolServerConfig<StratumConfig> =
         read_config(&stratum_cfg_path, || Btc::default_stratum_config())?;
 
+    let p2p_cfg_path = buf.join("config/p2p.json").into_boxed_path();
+
+    // a pool's network is in its p2p config, without one it has to be given
+    let config_network = fs::read_to_string(&p2p_cfg_path)
+        .ok()
+        .and_then(|data| {
+            serde_json::from_str::<ProtocolServerConfig<ConfigP2P<bitcoin::Block>>>(&data).ok()
+        })
+        .map(|config| config.protocol_config.consensus.network);
+    let network = cli.network.or(config_network).ok_or(format!(
+        "No network given, pass --network or set it in {}",
+        p2p_cfg_path.display()
+    ))?;
+
     if let Some(cmd) = cli.command {
+        check_daemon(
+            &stratum_config.protocol_config.rpc_url,
+            &stratum_config.protocol_config.rpc_auth,
+            network,
+        )?;
+
         if let SubCommands::CreatePool(params) = cmd {
-            create_pool(buf, params, stratum_config);
+            create_pool(buf, params, stratum_config, network);
         }
         return Ok(());
     }
 
-    let p2p_cfg_path = buf.join("config/p2p.json").into_boxed_path();
-
     let p2p_config: ProtocolServerConfig<ConfigP2P<bitcoin::Block>> =
         read_config(&p2p_cfg_path, || {
-            <Btc as Coin>::main_pool_config(buf.into_boxed_path().clone())
+            <Btc as Coin>::main_pool_config(buf.into_boxed_path().clone(), network)
         })?;
 
+    let network = p2p_config.protocol_config.consensus.network;
+    if let Some(cli_network) = cli.network {
+        if cli_network != network {
+            return Err(format!(
+                "The network given on the command line ({}) differs from the one in {} ({})",
+                cli_network,
+                p2p_cfg_path.display(),
+                network
+            ));
+        }
+    }
+
+    check_daemon(
+        &stratum_config.protocol_config.rpc_url,
+        &stratum_config.protocol_config.rpc_auth,
+        network,
+    )?;
+    check_daemon(
+        &p2p_config.protocol_config.rpc_url,
+        &p2p_config.protocol_config.rpc_auth,
+        network,
+    )?;
+
     info!("Stratum config: {:#?}", &stratum_config);
     info!("P2P config: {:#?}", &p2p_config);
 
@@ -145,15 +214,19 @@ fn create_pool(
     data_dir: PathBuf,
     params: CreatePoolParams,
     stratum_config: ProtocolServerConfig<StratumConfig>,
+    network: Network,
 ) {
     info!("Creating pool with: {:#?}", params);
 
     let rpc_url = stratum_config.protocol_config.rpc_url;
+    let rpc_auth = stratum_config.protocol_config.rpc_auth;
     // unfinished config, need to mine the first share.
     let mut new_config = ProtocolP2P::<Btc>::get_new_pool_config(
         data_dir.clone().into_boxed_path(),
         params.name.clone(),
         rpc_url.clone(),
+        rpc_auth.clone(),
+        network,
         params.diff1,
         1000,
     );
@@ -173,9 +246,16 @@ fn create_pool(
         },
         protocol_config: StratumConfig {
             rpc_url,
+            rpc_auth,
             // we only need a single job, one share.
             job_poll_interval_ms: 1000,
             default_diff_units: params.diff1,
+            vardiff: VardiffConfig {
+                min_diff_units: params.diff1,
+                max_diff_units: params.diff1,
+                ..Default::default()
+            },
+            zmq_hashblock: None,
         },
     };
     let pool_name = params.name;